@@ -0,0 +1,47 @@
+//! Compares decoding template data by walking the template with a visitor against
+//! running the compiled program. Run with `cargo bench`.
+
+use std::fs;
+use std::time::{Duration, Instant};
+use bitreader::BitReader;
+use rustbufrkit::decoder::{decode_binary, table_group_id, DecodeLimits};
+use rustbufrkit::payload::{decode_with_program, decode_with_visitor, encode_with_program, PayloadReader};
+use rustbufrkit::table::table::TableGroupManager;
+use rustbufrkit::table::template::DEFAULT_MAX_DEPTH;
+use rustbufrkit::Position;
+
+const N_SUBSETS: usize = 20_000;
+const N_RUNS: u32 = 10;
+
+fn main() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let table_group = table_group_manager.get_table_group(&table_group_id(message.header())).unwrap();
+    let ids = message.section(3).unwrap()
+        .field_by_name("unexpanded_descriptors").unwrap()
+        .try_get_unexpanded_descriptors().unwrap();
+    let (template, program) = table_group_manager.get_compiled(&table_group, ids, DEFAULT_MAX_DEPTH).unwrap();
+
+    // Many copies of the subsets of the test message
+    let subsets = message.section(4).unwrap()
+        .field_by_name("template_data").unwrap()
+        .try_get_subsets().unwrap();
+    let subsets: Vec<_> = subsets.iter().cycle().take(N_SUBSETS).cloned().collect();
+    let payload = encode_with_program(&program, &subsets).unwrap();
+
+    let time = |name: &str, decode: &dyn Fn(PayloadReader)| {
+        let mut total = Duration::default();
+        for _ in 0..N_RUNS {
+            let mut br = BitReader::new(&payload);
+            let reader = PayloadReader::new(
+                &table_group, &mut br, N_SUBSETS, false, DecodeLimits::default(), Position::default());
+            let start = Instant::now();
+            decode(reader);
+            total += start.elapsed();
+        }
+        println!("{:<8} {} subsets: {:?} per run", name, N_SUBSETS, total / N_RUNS);
+    };
+    time("visitor", &|reader| { decode_with_visitor(&template, reader).unwrap(); });
+    time("program", &|reader| { decode_with_program(&program, reader).unwrap(); });
+}
@@ -1,7 +1,10 @@
 use std::any::Any;
 use std::collections::HashMap;
+use std::fmt;
 use std::iter::FromIterator;
-use crate::BufrKitError;
+use std::str::FromStr;
+use crate::{BufrKitError, Position};
+use crate::table::descriptor::Fxy;
 
 lazy_static! {
     static ref MISSING_BITS_LOOKUP: HashMap<u8, u64> = {
@@ -15,22 +18,44 @@ lazy_static! {
 
 #[derive(Debug)]
 pub struct BufrMessage {
+    header: Section1Header,
     sections: Vec<BufrSection>,
+    warnings: Vec<Warning>,
 }
 
 impl BufrMessage {
-    pub fn new(sections: Vec<BufrSection>) -> BufrMessage {
+    pub fn new(header: Section1Header, sections: Vec<BufrSection>, warnings: Vec<Warning>) -> BufrMessage {
         BufrMessage {
+            header,
             sections,
+            warnings,
         }
     }
 
-    pub fn length(&self) -> u32 {
-        self.sections[0].length()
+    pub fn header(&self) -> &Section1Header {
+        &self.header
     }
 
-    pub fn edition(&self) -> u32 {
-        self.sections[0].fields[2].get_u32()
+    /// Problems found while decoding that did not stop it
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn try_length(&self) -> Option<u32> {
+        self.sections.first()?.try_length()
+    }
+
+    pub fn try_edition(&self) -> Option<u32> {
+        self.sections.first()?.field_by_name("edition")?.try_get_u32()
+    }
+
+    pub fn section(&self, index: u8) -> Option<&BufrSection> {
+        self.sections.get(index as usize)
+    }
+
+    /// Values of the subset with the given index, if it was decoded
+    pub fn subset(&self, index: usize) -> Option<&[DataValue]> {
+        self.section(4)?.field_by_name("template_data")?.try_get_subset(index)
     }
 
     pub fn test(&mut self) {
@@ -38,6 +63,205 @@ impl BufrMessage {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum WarningKind {
+    /// Reserved bits that should be zero are not
+    ReservedBits,
+    /// Section lengths disagree with each other or with the total length
+    SectionLength,
+    /// Padding that should be zero is not, or is longer than needed
+    Padding,
+    /// A code table value without an entry in the code table
+    UnknownCodeValue,
+    /// An element refers to a code or flag table that the table group does not have
+    MissingCodeTable,
+    /// The template uses an operator the decoder does not support, so its data is not decoded
+    UnsupportedOperator,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            WarningKind::ReservedBits => "reserved bits",
+            WarningKind::SectionLength => "section length",
+            WarningKind::Padding => "padding",
+            WarningKind::UnknownCodeValue => "unknown code value",
+            WarningKind::MissingCodeTable => "missing code table",
+            WarningKind::UnsupportedOperator => "unsupported operator",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A problem found while decoding that did not stop it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The descriptor being decoded, if the problem is in section 4 data
+    pub descriptor: Option<Fxy>,
+    pub position: Position,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.kind)?;
+        if let Some(descriptor) = self.descriptor {
+            write!(f, " {}", descriptor)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// The headers of a message, as found by `decoder::scan` without decoding its data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageSummary {
+    pub index: usize,
+    /// Offset of the start signature in the input
+    pub offset: u64,
+    pub length: usize,
+    pub header: Section1Header,
+    pub n_subsets: usize,
+    pub is_compressed: bool,
+    pub unexpanded_descriptors: Vec<Fxy>,
+}
+
+impl fmt::Display for MessageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = &self.header;
+        write!(f, "{}\t{}\t{}\tedition {}\tcentre {}/{}\tcategory {}/{}\t{}\t{} subset(s){}\t",
+               self.index, self.offset, self.length, header.edition, header.centre, header.subcentre,
+               header.data_category, header.data_i18n_subcategory.unwrap_or(header.data_local_subcategory),
+               header.timestamp, self.n_subsets, if self.is_compressed { " compressed" } else { "" })?;
+        let ids: Vec<String> = self.unexpanded_descriptors.iter().map(|id| id.to_string()).collect();
+        write!(f, "{}", ids.join(" "))
+    }
+}
+
+/// Section 1 fields normalised across editions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Section1Header {
+    pub edition: u8,
+    pub master_table_number: u8,
+    pub centre: u16,
+    /// Zero for edition 2, which has no sub-centre
+    pub subcentre: u16,
+    pub update_sequence_number: u8,
+    pub has_section2: bool,
+    pub data_category: u8,
+    /// Only present from edition 4
+    pub data_i18n_subcategory: Option<u8>,
+    pub data_local_subcategory: u8,
+    pub master_table_version: u8,
+    pub local_table_version: u8,
+    /// Seconds are zero before edition 4, which has no second
+    pub timestamp: Timestamp,
+}
+
+impl Section1Header {
+    pub fn from_section(edition: u8, section: &BufrSection) -> Result<Self, BufrKitError> {
+        let u32_of = |name: &str| section.field_by_name(name)
+            .and_then(|f| f.try_get_u32())
+            .ok_or_else(|| BufrKitError::InvalidSection {
+                section: 1,
+                message: format!("{}: field not found", name),
+            });
+        let optional_u32_of = |name: &str| if section.field_by_name(name).is_some() {
+            u32_of(name).map(Some)
+        } else {
+            Ok(None)
+        };
+
+        let year = u32_of("year")? as u16;
+        Ok(Section1Header {
+            edition,
+            master_table_number: optional_u32_of("master_table_number")?.unwrap_or(0) as u8,
+            centre: u32_of("originating_centre")? as u16,
+            subcentre: optional_u32_of("originating_subcentre")?.unwrap_or(0) as u16,
+            update_sequence_number: u32_of("update_sequence_number")? as u8,
+            has_section2: section.field_by_name("is_section2_presents")
+                .and_then(|f| f.try_get_bool())
+                .ok_or_else(|| BufrKitError::InvalidSection {
+                    section: 1,
+                    message: "is_section2_presents: field not found".to_owned(),
+                })?,
+            data_category: u32_of("data_category")? as u8,
+            data_i18n_subcategory: optional_u32_of("data_i18n_subcategory")?.map(|x| x as u8),
+            data_local_subcategory: u32_of("data_local_subcategory")? as u8,
+            master_table_version: u32_of("master_table_version")? as u8,
+            local_table_version: u32_of("local_table_version")? as u8,
+            timestamp: Timestamp {
+                year: if edition < 4 { full_year(year) } else { year },
+                month: u32_of("month")? as u8,
+                day: u32_of("day")? as u8,
+                hour: u32_of("hour")? as u8,
+                minute: u32_of("minute")? as u8,
+                second: optional_u32_of("second")?.unwrap_or(0) as u8,
+            },
+        })
+    }
+}
+
+/// Editions before 4 store the year of century. Years 51 to 99 are taken as 1900s
+/// and the rest, including 100 used by some centres for 2000, as 2000s.
+fn full_year(year_of_century: u16) -> u16 {
+    if year_of_century > 50 && year_of_century < 100 {
+        1900 + year_of_century
+    } else {
+        2000 + year_of_century % 100
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Parses the format of `Display`, where the time or its trailing parts may be left
+/// out and default to zero, e.g. `2016-02-18` or `2016-02-18T23:30`
+impl FromStr for Timestamp {
+    type Err = BufrKitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BufrKitError::Other(format!("invalid timestamp: {}", s));
+        let (date, time) = match s.split_once(['T', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+        let date: Vec<&str> = date.split('-').collect();
+        let time: Vec<&str> = time.map_or_else(Vec::new, |time| time.split(':').collect());
+        if date.len() != 3 || time.len() > 3 || time.len() == 1 && time[0].is_empty() {
+            return Err(invalid());
+        }
+        let part = |parts: &[&str], i: usize| parts.get(i).map_or(Ok(0), |p| p.parse::<u8>()).map_err(|_| invalid());
+        let timestamp = Timestamp {
+            year: date[0].parse().map_err(|_| invalid())?,
+            month: part(&date, 1)?,
+            day: part(&date, 2)?,
+            hour: part(&time, 0)?,
+            minute: part(&time, 1)?,
+            second: part(&time, 2)?,
+        };
+        if !(1..=12).contains(&timestamp.month) || !(1..=31).contains(&timestamp.day)
+            || timestamp.hour > 23 || timestamp.minute > 59 || timestamp.second > 59 {
+            return Err(invalid());
+        }
+        Ok(timestamp)
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BufrSection {
     index: u8,
@@ -56,64 +280,65 @@ impl BufrSection {
         }
     }
 
-    pub fn length(&self) -> u32 {
-        return if self.index == 0 {
-            self.fields[1].get_u32()
-        } else if self.index == 2 && self.fields.len() == 0 {
-            0
-        } else {
-            self.fields[0].get_u32()
-        };
+    pub fn index(&self) -> u8 {
+        self.index
     }
 
-    pub fn field_by_name(&self, name: &str) -> Option<&Field> {
-        if let Some(i) = self.lookup.get(name) {
-            Some(self.field(*i))
+    pub fn try_length(&self) -> Option<u32> {
+        if self.index == 0 {
+            self.field(1)?.try_get_u32()
+        } else if self.index == 2 && self.fields.is_empty() {
+            Some(0)
         } else {
-            None
+            self.field(0)?.try_get_u32()
         }
     }
 
-    pub fn field(&self, i: usize) -> &Field {
-        &self.fields[i]
+    pub fn field_by_name(&self, name: &str) -> Option<&Field> {
+        self.field(*self.lookup.get(name)?)
     }
-}
 
+    pub fn field(&self, i: usize) -> Option<&Field> {
+        self.fields.get(i)
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SimpleData {
     U32(u32),
+    I64(i64),
     F64(f64),
     BYTES(String),
     FLAG(u32, u8),
     // value, nbits
     BOOL(bool),
     RAW(Vec<u8>, usize), // value, nbits
+    MISSING,
 }
 
 impl SimpleData {
-    pub fn get_u32(&self) -> u32 {
-        return if let SimpleData::U32(v) = self {
-            *v
+    pub fn try_get_u32(&self) -> Option<u32> {
+        if let SimpleData::U32(v) = self {
+            Some(*v)
         } else {
-            panic!("{:?}: cannot get u32 value", self)
-        };
+            None
+        }
     }
 
-    pub fn get_bytes(&self) -> &str {
-        return if let SimpleData::BYTES(v) = self {
-            &v
+    pub fn try_get_bytes(&self) -> Option<&str> {
+        if let SimpleData::BYTES(v) = self {
+            Some(v)
         } else {
-            panic!("{:?}: cannot get str value", self)
-        };
+            None
+        }
     }
 
-    pub fn get_bool(&self) -> bool {
-        return if let SimpleData::BOOL(v) = self {
-            *v
+    pub fn try_get_bool(&self) -> Option<bool> {
+        if let SimpleData::BOOL(v) = self {
+            Some(*v)
         } else {
-            panic!("{:?}: cannot get bool value", self)
-        };
+            None
+        }
     }
 }
 
@@ -133,32 +358,50 @@ impl Field {
         }
     }
 
-    pub fn get_simple_data(&self) -> &SimpleData {
+    pub fn try_get_simple_data(&self) -> Option<&SimpleData> {
         if let Field::SIMPLE(f) = self {
-            &f.value
+            Some(&f.value)
         } else {
-            panic!("{:?}: cannot get simple value", self)
+            None
         }
     }
 
-    pub fn get_unexpanded_descriptors(&self) -> &Vec<isize> {
+    pub fn try_get_unexpanded_descriptors(&self) -> Option<&[Fxy]> {
         if let Field::UED(f) = self {
-            &f.value
+            Some(&f.value)
+        } else {
+            None
+        }
+    }
+
+    /// The decoded subsets, which may start after the first subset of the message
+    pub fn try_get_subsets(&self) -> Option<&[Vec<DataValue>]> {
+        if let Field::PAYLOAD(f) = self {
+            Some(&f.value)
         } else {
-            panic!("cannot get unexpanded descriptors: {:?}", self)
+            None
+        }
+    }
+
+    /// Values of the subset with the given index in the message, if it was decoded
+    pub fn try_get_subset(&self, index: usize) -> Option<&[DataValue]> {
+        if let Field::PAYLOAD(f) = self {
+            f.value.get(index.checked_sub(f.first_subset)?).map(|subset| &subset[..])
+        } else {
+            None
         }
     }
 
-    pub fn get_u32(&self) -> u32 {
-        self.get_simple_data().get_u32()
+    pub fn try_get_u32(&self) -> Option<u32> {
+        self.try_get_simple_data()?.try_get_u32()
     }
 
-    pub fn get_bytes(&self) -> &str {
-        self.get_simple_data().get_bytes()
+    pub fn try_get_bytes(&self) -> Option<&str> {
+        self.try_get_simple_data()?.try_get_bytes()
     }
 
-    pub fn get_bool(&self) -> bool {
-        self.get_simple_data().get_bool()
+    pub fn try_get_bool(&self) -> Option<bool> {
+        self.try_get_simple_data()?.try_get_bool()
     }
 }
 
@@ -177,24 +420,38 @@ impl FieldSimple {
 #[derive(Debug)]
 pub struct FieldUED {
     name: String,
-    value: Vec<isize>,
+    value: Vec<Fxy>,
 }
 
 impl FieldUED {
-    pub fn new(name: &str, value: Vec<isize>) -> Self {
+    pub fn new(name: &str, value: Vec<Fxy>) -> Self {
         FieldUED { name: name.to_owned(), value }
     }
 }
 
-// Template Data
+/// A value decoded from template data and the descriptor it belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataValue {
+    pub id: Fxy,
+    pub value: SimpleData,
+}
+
+// Template Data, one list of values per subset
 #[derive(Debug)]
 pub struct FieldPayload {
     name: String,
-    value: Vec<SimpleData>,
+    /// Index of the first subset in `value`, which is not zero when only some
+    /// subsets are decoded
+    first_subset: usize,
+    value: Vec<Vec<DataValue>>,
 }
 
 impl FieldPayload {
-    pub fn new(name: &str, value: Vec<SimpleData>) -> Self {
-        FieldPayload { name: name.to_owned(), value }
+    pub fn new(name: &str, value: Vec<Vec<DataValue>>) -> Self {
+        FieldPayload::with_first_subset(name, 0, value)
+    }
+
+    pub fn with_first_subset(name: &str, first_subset: usize, value: Vec<Vec<DataValue>>) -> Self {
+        FieldPayload { name: name.to_owned(), first_subset, value }
     }
 }
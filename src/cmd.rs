@@ -1,68 +1,447 @@
-use std::io::BufReader;
-use crate::decoder::decode_binary;
-use crate::BufrKitError;
+use crate::decoder::{decode_messages_parallel, decode_stream, scan, DecodeOptions};
+use crate::input::{expand_inputs, Input, Rewind};
+use crate::bufr::{BufrMessage, MessageSummary, Warning, WarningKind};
+use crate::{BufrKitError, Position};
+use std::collections::BTreeMap;
 use std::io;
-use std::fs::File;
+use std::fs;
 use crate::table::template::{Template, PrintVisitor};
-use crate::table::table::{TableGroup, TableGroupId, TableGroupManager};
+use crate::table::table::{Entry, TableGroup, TableGroupId, TableGroupManager};
+use crate::table::check::check_table_group;
+use crate::table::diff::diff_table_groups;
+use crate::table::search::{NameMatcher, search, used_by};
+use crate::table::descriptor::Fxy;
+use std::path::{Path, PathBuf};
+use crate::index::{Index, IndexQuery, SourceInfo};
 
 pub trait Command {
     fn run(&mut self) -> Result<(), BufrKitError>;
 }
 
 pub struct DecodeCommand<'a> {
-    ins_name: &'a str
+    ins_names: Vec<&'a str>,
+    options: DecodeOptions,
+    n_threads: usize,
 }
 
 impl<'a> DecodeCommand<'a> {
-    pub fn new(ins_name: &'a str) -> Self {
+    pub fn new(ins_names: Vec<&'a str>, options: DecodeOptions, n_threads: usize) -> Self {
         DecodeCommand {
-            ins_name,
+            ins_names,
+            options,
+            n_threads,
+        }
+    }
+
+    fn decode(&self,
+              table_group_manager: &TableGroupManager,
+              path: &Path,
+              warning_summary: &mut WarningSummary,
+              n_failed: &mut usize) -> Result<(), BufrKitError> {
+        // Compressed inputs are decoded one message at a time as they are decompressed
+        match open_input(path)? {
+            Input::Mapped(mmap) => self.print(
+                decode_messages_parallel(table_group_manager, &mmap, &self.options, self.n_threads), path, warning_summary, n_failed),
+            Input::Owned(bytes) => self.print(
+                decode_messages_parallel(table_group_manager, &bytes, &self.options, self.n_threads), path, warning_summary, n_failed),
+            Input::Decompressed(r) => self.print(
+                decode_stream(table_group_manager, Rewind::new(r, self.options.limits.max_message_size), &self.options),
+                path, warning_summary, n_failed),
+        }
+    }
+
+    fn print(&self,
+             messages: impl Iterator<Item = Result<BufrMessage, BufrKitError>>,
+             path: &Path,
+             warning_summary: &mut WarningSummary,
+             n_failed: &mut usize) -> Result<(), BufrKitError> {
+        for result in messages {
+            match result {
+                Ok(bufr_message) => {
+                    warning_summary.add(bufr_message.warnings());
+                    println!("{:?}", bufr_message)
+                }
+                Err(e) if self.options.lenient => {
+                    eprintln!("{}: {}", path.display(), e);
+                    *n_failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
     }
 }
 
 impl<'a> Command for DecodeCommand<'a> {
     fn run(&mut self) -> Result<(), BufrKitError> {
         let table_group_manager = TableGroupManager::new();
-        let bufr_message = if self.ins_name == "-" {
-            decode_binary(&table_group_manager, &mut io::stdin().lock())?
-        } else {
-            let file = File::open(self.ins_name).unwrap();
-            decode_binary(&table_group_manager, &mut BufReader::new(file))?
-        };
-        println!("{:?}", bufr_message);
+        let inputs = expand_inputs(&self.ins_names);
+        let show_names = inputs.len() > 1;
+        let mut n_failed = 0;
+        let mut n_failed_inputs = 0;
+        let mut warning_summary = WarningSummary::default();
+        for input in inputs {
+            let result = input.and_then(|path| {
+                if show_names {
+                    println!("{}:", path.display());
+                }
+                self.decode(&table_group_manager, &path, &mut warning_summary, &mut n_failed)
+                    .map_err(|e| BufrKitError::Other(format!("{}: {}", path.display(), e)))
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                n_failed_inputs += 1;
+            }
+        }
+        warning_summary.print();
+        check_failed("decode", n_failed, n_failed_inputs)
+    }
+}
+
+pub struct LsCommand<'a> {
+    ins_names: Vec<&'a str>,
+    options: DecodeOptions,
+}
+
+impl<'a> LsCommand<'a> {
+    pub fn new(ins_names: Vec<&'a str>, options: DecodeOptions) -> Self {
+        LsCommand {
+            ins_names,
+            options,
+        }
+    }
+
+    /// List the messages of one input, each prefixed with `prefix`
+    fn list(&self, input: Input, prefix: &str, n_failed: &mut usize) -> Result<(), BufrKitError> {
+        for result in scan_input(input, &self.options) {
+            match result {
+                Ok(summary) => println!("{}{}", prefix, summary),
+                Err(e) if self.options.lenient => {
+                    eprintln!("{}", e);
+                    *n_failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Command for LsCommand<'a> {
+    fn run(&mut self) -> Result<(), BufrKitError> {
+        let inputs = expand_inputs(&self.ins_names);
+        let show_names = inputs.len() > 1;
+        let mut n_failed = 0;
+        let mut n_failed_inputs = 0;
+        for input in inputs {
+            let result = input.and_then(|path| {
+                let prefix = if show_names { format!("{}\t", path.display()) } else { String::new() };
+                open_input(&path)
+                    .and_then(|input| self.list(input, &prefix, &mut n_failed))
+                    .map_err(|e| BufrKitError::Other(format!("{}: {}", path.display(), e)))
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                n_failed_inputs += 1;
+            }
+        }
+        check_failed("scan", n_failed, n_failed_inputs)
+    }
+}
+
+/// Read stdin for `-`, otherwise map the file
+fn open_input(path: &Path) -> Result<Input, BufrKitError> {
+    if path == Path::new("-") {
+        Input::stdin()
+    } else {
+        // SAFETY: inputs are archives that are not expected to change while they are decoded
+        unsafe { Input::map(path) }
+    }
+}
+
+/// Scan the messages of the input. Offsets of a compressed input are positions in its
+/// decompressed bytes.
+fn scan_input(input: Input, options: &DecodeOptions) -> Box<dyn Iterator<Item = Result<MessageSummary, BufrKitError>> + '_> {
+    match input {
+        Input::Mapped(mmap) => Box::new(scan(io::Cursor::new(mmap), options)),
+        Input::Owned(bytes) => Box::new(scan(io::Cursor::new(bytes), options)),
+        Input::Decompressed(r) => Box::new(scan(Rewind::new(r, options.limits.max_message_size), options)),
+    }
+}
+
+/// Fail when any message or input failed, so that a lenient run that skipped some
+/// still exits with an error
+fn check_failed(action: &str, n_failed: usize, n_failed_inputs: usize) -> Result<(), BufrKitError> {
+    let mut failures = Vec::new();
+    if n_failed > 0 {
+        failures.push(format!("{} message(s) failed to {}", n_failed, action));
+    }
+    if n_failed_inputs > 0 {
+        failures.push(format!("{} input(s) failed", n_failed_inputs));
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(BufrKitError::Other(failures.join(", ")))
+    }
+}
+
+pub struct IndexBuildCommand<'a> {
+    ins_name: &'a str,
+    /// Where to save the index, next to the input by default
+    index_name: Option<&'a str>,
+    options: DecodeOptions,
+}
+
+impl<'a> IndexBuildCommand<'a> {
+    pub fn new(ins_name: &'a str, index_name: Option<&'a str>, options: DecodeOptions) -> Self {
+        IndexBuildCommand {
+            ins_name,
+            index_name,
+            options,
+        }
+    }
+}
+
+impl<'a> Command for IndexBuildCommand<'a> {
+    fn run(&mut self) -> Result<(), BufrKitError> {
+        if self.ins_name == "-" {
+            return Err(BufrKitError::Other("cannot index stdin, the index is kept next to the input file".to_owned()));
+        }
+        let input = open_input(Path::new(self.ins_name))?;
+        let mut index = Index::new(SourceInfo::of(self.ins_name)?);
+        let table_group_manager = TableGroupManager::new();
+        let mut n_failed = 0;
+        for result in scan_input(input, &self.options) {
+            match result {
+                Ok(summary) => {
+                    let entry = index.push(summary, &table_group_manager, &self.options.limits);
+                    if let Some(e) = &entry.expansion_error {
+                        eprintln!("message {}: descriptors not indexed: {}", entry.summary.index, e);
+                    }
+                }
+                Err(e) if self.options.lenient => {
+                    eprintln!("{}", e);
+                    n_failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let index_path = self.index_name.map_or_else(|| Index::path_for(self.ins_name), PathBuf::from);
+        index.save(&index_path)?;
+        eprintln!("{}: {} message(s) indexed", index_path.display(), index.entries().len());
+        if n_failed > 0 {
+            eprintln!("{} message(s) failed to scan", n_failed);
+        }
+        Ok(())
+    }
+}
+
+pub struct IndexQueryCommand<'a> {
+    ins_name: &'a str,
+    /// Where the index was saved, next to the input by default
+    index_name: Option<&'a str>,
+    query: IndexQuery,
+}
+
+impl<'a> IndexQueryCommand<'a> {
+    pub fn new(ins_name: &'a str, index_name: Option<&'a str>, query: IndexQuery) -> Self {
+        IndexQueryCommand {
+            ins_name,
+            index_name,
+            query,
+        }
+    }
+}
+
+impl<'a> Command for IndexQueryCommand<'a> {
+    fn run(&mut self) -> Result<(), BufrKitError> {
+        let index_path = self.index_name.map_or_else(|| Index::path_for(self.ins_name), PathBuf::from);
+        let index = Index::load(&index_path)?;
+        if &SourceInfo::of(self.ins_name)? != index.source() {
+            return Err(BufrKitError::Other(format!(
+                "{}: out of date for {}, rebuild it with index build", index_path.display(), self.ins_name)));
+        }
+        for entry in index.query(&self.query) {
+            println!("{}", entry);
+        }
         Ok(())
     }
 }
 
+/// Warnings counted by kind and descriptor, with where each was first seen
+#[derive(Default)]
+struct WarningSummary {
+    counts: BTreeMap<(WarningKind, Option<Fxy>), (usize, Position)>,
+}
+
+impl WarningSummary {
+    fn add(&mut self, warnings: &[Warning]) {
+        for warning in warnings {
+            self.counts.entry((warning.kind, warning.descriptor))
+                .or_insert((0, warning.position))
+                .0 += 1;
+        }
+    }
+
+    fn print(&self) {
+        if self.counts.is_empty() {
+            return;
+        }
+        eprintln!("{} warning(s):", self.counts.values().map(|(n, _)| n).sum::<usize>());
+        for ((kind, descriptor), (n, position)) in self.counts.iter() {
+            match descriptor {
+                Some(id) => eprintln!("  {} {}: {} (first at {})", kind, id, n, position),
+                None => eprintln!("  {}: {} (first at {})", kind, n, position),
+            }
+        }
+    }
+}
+
+pub enum LookupQuery<'a> {
+    /// Comma separated list of descriptor IDs to expand
+    Ids(&'a str),
+    /// Comma separated list of element descriptor IDs to show code and flag tables for
+    Code(&'a str),
+    /// Comma separated list of descriptor IDs to show class descriptions for
+    Meta(&'a str),
+    /// Keywords or regular expression to match against descriptor names
+    Search(&'a str, bool),
+    /// Descriptor ID to find the containing sequences for
+    UsedBy(&'a str),
+}
+
 pub struct LookupCommand<'a> {
-    ids: &'a str,
+    table_group_id: TableGroupId,
+    query: LookupQuery<'a>,
 }
 
 impl<'a> LookupCommand<'a> {
-    pub fn new(ids: &'a str) -> Self {
+    pub fn new(table_group_id: TableGroupId, query: LookupQuery<'a>) -> Self {
         LookupCommand {
-            ids,
+            table_group_id,
+            query,
         }
     }
 }
 
+pub(crate) fn parse_ids(s: &str) -> Result<Vec<Fxy>, BufrKitError> {
+    let mut ids = Vec::new();
+    for s in s.split(',') {
+        ids.push(s.parse::<Fxy>()?);
+    }
+    Ok(ids)
+}
+
 impl<'a> Command for LookupCommand<'a> {
     fn run(&mut self) -> Result<(), BufrKitError> {
-        let mut ids = Vec::new();
-        for s in self.ids.split(",") {
-            ids.push(s.parse::<isize>()?);
-        }
-        let table_group = TableGroupManager::new().get_table_group(&TableGroupId {
-            base_dir: String::from("_definitions/tables"),
-            master_table_number: 0,
-            centre_number: 0,
-            sub_centre_number: 0,
-            version_number: 25,
-        }).unwrap();
-        let template = Template::new(&table_group, &ids)?;
-        template.accept(&mut PrintVisitor::new());
+        let table_group = TableGroupManager::new().get_table_group(&self.table_group_id)?;
+        match self.query {
+            LookupQuery::Ids(s) => {
+                let template = Template::new(&table_group, &parse_ids(s)?)?;
+                template.accept(&mut PrintVisitor::new())?;
+            }
+            LookupQuery::Code(s) => {
+                for id in parse_ids(s)? {
+                    let name = match table_group.lookup(id)? {
+                        Entry::B(bentry) => &bentry.name,
+                        _ => "",
+                    };
+                    println!("{} {}", id, name);
+                    let mut values: Vec<isize> = table_group.code_and_flag().get(id)
+                        .ok_or_else(|| BufrKitError::Other(format!("{}: code or flag table not found", id)))?
+                        .keys().cloned().collect();
+                    values.sort_unstable();
+                    for value in values {
+                        println!("    {} {}", value, table_group.lookup_cnf(id, value)?);
+                    }
+                }
+            }
+            LookupQuery::Meta(s) => {
+                for id in parse_ids(s)? {
+                    println!("{} {}", id, table_group.lookup_meta(id)?);
+                }
+            }
+            LookupQuery::Search(pattern, is_regex) => {
+                let matcher = if is_regex {
+                    NameMatcher::regex(pattern)?
+                } else {
+                    NameMatcher::keywords(pattern)
+                };
+                for (id, name) in search(&table_group, &matcher) {
+                    println!("{} {}", id, name);
+                }
+            }
+            LookupQuery::UsedBy(s) => {
+                for (id, direct) in used_by(&table_group, s.parse::<Fxy>()?) {
+                    let name = table_group.table_d().get(id).map_or("", |dentry| &dentry.name);
+                    println!("{} {}{}", id, name, if direct { "" } else { " (indirect)" });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct TablesCheckCommand<'a> {
+    dir: &'a str,
+}
+
+impl<'a> TablesCheckCommand<'a> {
+    pub fn new(dir: &'a str) -> Self {
+        TablesCheckCommand {
+            dir,
+        }
+    }
+}
+
+impl<'a> Command for TablesCheckCommand<'a> {
+    fn run(&mut self) -> Result<(), BufrKitError> {
+        let table_group = TableGroup::load(&TableGroupId::from_path(self.dir)?)?;
+        let problems = check_table_group(&table_group);
+        for problem in problems.iter() {
+            println!("{}", problem);
+        }
+        if problems.is_empty() {
+            println!("{}: no problems found", table_group.id());
+            Ok(())
+        } else {
+            Err(BufrKitError::Other(format!("{}: {} problem(s) found", table_group.id(), problems.len())))
+        }
+    }
+}
+
+pub struct TablesDiffCommand<'a> {
+    base_dir: &'a str,
+    old: &'a str,
+    new: &'a str,
+    json: bool,
+}
+
+impl<'a> TablesDiffCommand<'a> {
+    pub fn new(base_dir: &'a str, old: &'a str, new: &'a str, json: bool) -> Self {
+        TablesDiffCommand {
+            base_dir,
+            old,
+            new,
+            json,
+        }
+    }
+
+    fn load(&self, path: &str) -> Result<TableGroup, BufrKitError> {
+        let dir = Path::new(self.base_dir).join(path);
+        TableGroup::load(&TableGroupId::from_path(&dir.to_string_lossy())?)
+    }
+}
+
+impl<'a> Command for TablesDiffCommand<'a> {
+    fn run(&mut self) -> Result<(), BufrKitError> {
+        let diff = diff_table_groups(&self.load(self.old)?, &self.load(self.new)?);
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        } else {
+            println!("{}", diff);
+        }
         Ok(())
     }
 }
@@ -1,36 +1,536 @@
-use std::io::BufRead;
-use crate::bufr::{BufrMessage, BufrSection, Field, FieldSimple, FieldUED, SimpleData};
-use crate::BufrKitError;
+use std::io::{BufRead, Seek, SeekFrom};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use memchr::memmem;
+use crate::bufr::{BufrMessage, BufrSection, Field, FieldPayload, FieldSimple, FieldUED, MessageSummary, Section1Header,
+                  SimpleData, Warning, WarningKind};
+use crate::payload::{decode_with_program, unsupported_operator, PayloadReader};
+use crate::payload::program::Program;
+use crate::input::decompressed;
+use crate::{BufrKitError, Position};
 use bitreader::{BitReader, BitReaderError};
 use std::borrow::{Borrow, BorrowMut};
 use crate::table::table::{TableGroupManager, TableGroupId, TableGroup};
-use crate::table::template::{Template, PrintVisitor};
+use crate::table::template::{Template, PrintVisitor, DEFAULT_MAX_DEPTH};
+use crate::table::descriptor::Fxy;
 
+/// Options controlling how decoding deals with malformed input and what it decodes
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// Report a malformed message and resume from the next start signature
+    /// instead of stopping at the first error. Inconsistencies that do not
+    /// prevent decoding, such as non-zero reserved bits, become warnings
+    /// instead of errors.
+    pub lenient: bool,
+    pub limits: DecodeLimits,
+    /// Decode the values of this subset only, skipping the others
+    pub subset: Option<usize>,
+    /// Decode the values of these descriptors only, skipping the others
+    pub projection: Option<HashSet<Fxy>>,
+}
+
+/// Bounds on how much work and memory a message can ask of the decoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Largest total message length in bytes
+    pub max_message_size: usize,
+    pub max_subsets: usize,
+    /// Largest delayed replication factor in section 4 data
+    pub max_replication_count: usize,
+    /// Deepest nesting of sequences and replications when expanding descriptors
+    pub max_expansion_depth: usize,
+    /// Most values decoded from section 4 data, across all subsets
+    pub max_output_values: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_message_size: 16 * 1024 * 1024,
+            max_subsets: 65535,
+            max_replication_count: 65535,
+            max_expansion_depth: DEFAULT_MAX_DEPTH,
+            max_output_values: 10_000_000,
+        }
+    }
+}
+
+
+/// Decode the first message read from the reader, which is decompressed first if it
+/// is gzip, bzip2 or zstd compressed
 pub fn decode_binary(table_group_manager: &TableGroupManager,
                      r: &mut dyn BufRead) -> Result<BufrMessage, BufrKitError> {
-    let bytes = prepare(r)?;
-    let br = BitReader::new(bytes.borrow());
-    let mut bd = BinaryDecoder {
+    let options = DecodeOptions::default();
+    let (pos, bytes) = prepare(&mut *decompressed(r)?, &options.limits)?;
+    decode_message(table_group_manager, &bytes, 0, pos as u64, &options)
+}
+
+/// Decode the first message found in the bytes, straight from the slice
+pub fn decode_slice(table_group_manager: &TableGroupManager,
+                    bytes: &[u8],
+                    options: &DecodeOptions) -> Result<BufrMessage, BufrKitError> {
+    decode_messages(table_group_manager, bytes, options).next().unwrap_or(Err(BufrKitError::UnexpectedEof))
+}
+
+/// Decode every message found in the input, in order. Each failed message is
+/// reported with its position. Iteration ends after the first failure unless
+/// decoding is lenient.
+pub fn decode_messages<'a>(table_group_manager: &'a TableGroupManager,
+                           bytes: &'a [u8],
+                           options: &'a DecodeOptions) -> Messages<'a> {
+    Messages {
         table_group_manager,
-        br,
+        bytes,
+        options,
+        offset: 0,
+        message_index: 0,
+        done: false,
+    }
+}
+
+pub struct Messages<'a> {
+    table_group_manager: &'a TableGroupManager,
+    bytes: &'a [u8],
+    options: &'a DecodeOptions,
+    offset: usize,
+    message_index: usize,
+    done: bool,
+}
+
+/// A decoded message with its total length, or why it failed to decode
+type Decoded = Result<(BufrMessage, usize), BufrKitError>;
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Result<BufrMessage, BufrKitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(&mut |_, _| None)
+    }
+}
+
+impl<'a> Messages<'a> {
+    /// Move on to the next message. `decoded` may supply the result of decoding the
+    /// message with the given index at the given offset, otherwise it is decoded here.
+    fn next_with(&mut self, decoded: &mut dyn FnMut(usize, usize) -> Option<Decoded>) -> Option<Result<BufrMessage, BufrKitError>> {
+        if self.done {
+            return None;
+        }
+        let start = match find_start_signature(&self.bytes[self.offset..]) {
+            Some(pos) => self.offset + pos,
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+        let message_index = self.message_index;
+        self.message_index += 1;
+
+        let result = match decoded(message_index, start) {
+            Some(result) => result,
+            None => decode_at(self.table_group_manager, self.bytes, start, message_index, self.options),
+        };
+        match result {
+            Ok((message, length)) => {
+                self.offset = start + length;
+                Some(Ok(message))
+            }
+            Err(e) => {
+                if self.options.lenient {
+                    self.offset = start + 4;
+                } else {
+                    self.done = true;
+                }
+                Some(Err(e.at(Position {
+                    message_index,
+                    message_offset: start as u64,
+                    section: None,
+                    bit_offset: 0,
+                })))
+            }
+        }
+    }
+}
+
+/// Decode every message found in the input like `decode_messages`, decoding up to
+/// `n_threads` messages at a time. Messages come out in the same order and with
+/// the same results as from `decode_messages`.
+pub fn decode_messages_parallel<'a>(table_group_manager: &'a TableGroupManager,
+                                    bytes: &'a [u8],
+                                    options: &'a DecodeOptions,
+                                    n_threads: usize) -> ParallelMessages<'a> {
+    ParallelMessages {
+        messages: decode_messages(table_group_manager, bytes, options),
+        n_threads: n_threads.max(1),
+        ready: VecDeque::new(),
+    }
+}
+
+pub struct ParallelMessages<'a> {
+    messages: Messages<'a>,
+    n_threads: usize,
+    ready: VecDeque<Result<BufrMessage, BufrKitError>>,
+}
+
+impl<'a> Iterator for ParallelMessages<'a> {
+    type Item = Result<BufrMessage, BufrKitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ready.is_empty() {
+            self.decode_batch();
+        }
+        self.ready.pop_front()
+    }
+}
+
+impl<'a> ParallelMessages<'a> {
+    /// Messages decoded at a time, enough to keep the threads busy when sizes vary
+    fn batch_size(&self) -> usize {
+        self.n_threads * 4
+    }
+
+    fn decode_batch(&mut self) {
+        let messages = &self.messages;
+        if messages.done {
+            return;
+        }
+        // Assume the messages decode and follow each other, as they usually do
+        let mut frames = Vec::new();
+        let (mut offset, mut message_index) = (messages.offset, messages.message_index);
+        while frames.len() < self.batch_size() {
+            let start = match find_start_signature(&messages.bytes[offset..]) {
+                Some(pos) => offset + pos,
+                None => break,
+            };
+            match message_length(&messages.bytes[start..], &messages.options.limits) {
+                Ok(length) => {
+                    frames.push((message_index, start));
+                    offset = start + length;
+                    message_index += 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let mut decoded = self.decode_frames(&frames);
+        let mut frames = frames.into_iter().zip(decoded.drain(..)).peekable();
+        // Walk the messages as `decode_messages` does, taking the results decoded ahead
+        // whenever it arrives at them
+        loop {
+            let result = self.messages.next_with(&mut |index, start| {
+                while frames.peek().is_some_and(|&((i, s), _)| s < start || (s == start && i != index)) {
+                    frames.next();
+                }
+                match frames.peek() {
+                    Some(&((i, s), _)) if (i, s) == (index, start) => frames.next().map(|(_, result)| result),
+                    _ => None,
+                }
+            });
+            match result {
+                Some(result) => self.ready.push_back(result),
+                None => break,
+            }
+            if frames.peek().is_none() {
+                break;
+            }
+        }
+    }
+
+    fn decode_frames(&self, frames: &[(usize, usize)]) -> Vec<Decoded> {
+        let messages = &self.messages;
+        let next_frame = AtomicUsize::new(0);
+        let mut decoded: Vec<(usize, Decoded)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.n_threads.min(frames.len())).map(|_| scope.spawn(|| {
+                let mut decoded = Vec::new();
+                loop {
+                    let i = next_frame.fetch_add(1, Ordering::Relaxed);
+                    let (message_index, start) = match frames.get(i) {
+                        Some(&frame) => frame,
+                        None => return decoded,
+                    };
+                    decoded.push((i, decode_at(messages.table_group_manager, messages.bytes, start, message_index, messages.options)));
+                }
+            })).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        decoded.sort_by_key(|(i, _)| *i);
+        decoded.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+/// Decode every message read from the reader like `decode_messages`, one at a time, so
+/// that only the message being decoded is held in memory, e.g. for compressed inputs.
+/// The reader is only seeked back to resume after a failed message when decoding is
+/// lenient, and then by no more than the length of the message.
+pub fn decode_stream<'a, R: BufRead + Seek>(table_group_manager: &'a TableGroupManager,
+                                            r: R,
+                                            options: &'a DecodeOptions) -> StreamMessages<'a, R> {
+    StreamMessages { table_group_manager, r, options, message_index: 0, done: false }
+}
+
+pub struct StreamMessages<'a, R> {
+    table_group_manager: &'a TableGroupManager,
+    r: R,
+    options: &'a DecodeOptions,
+    message_index: usize,
+    done: bool,
+}
+
+impl<'a, R: BufRead + Seek> Iterator for StreamMessages<'a, R> {
+    type Item = Result<BufrMessage, BufrKitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let start = match find_string("BUFR", &mut self.r).and_then(|_| Ok(self.r.stream_position()? - 4)) {
+            Ok(start) => start,
+            Err(BufrKitError::UnexpectedEof) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let message_index = self.message_index;
+        self.message_index += 1;
+        let result = read_message(&mut self.r, b"BUFR", &self.options.limits)
+            .and_then(|bytes| decode_message(self.table_group_manager, &bytes, message_index, start, self.options));
+        if result.is_err() {
+            if !self.options.lenient {
+                self.done = true;
+            } else if let Err(e) = self.r.seek(SeekFrom::Start(start + 4)) {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        }
+        Some(result.map_err(|e| e.at(Position { message_index, message_offset: start, section: None, bit_offset: 0 })))
+    }
+}
+
+/// Decode the message starting at the given offset
+fn decode_at(table_group_manager: &TableGroupManager,
+             bytes: &[u8],
+             start: usize,
+             message_index: usize,
+             options: &DecodeOptions) -> Decoded {
+    let length = message_length(&bytes[start..], &options.limits)?;
+    decode_message(table_group_manager, &bytes[start..start + length], message_index, start as u64, options)
+        .map(|message| (message, length))
+}
+
+fn decode_message(table_group_manager: &TableGroupManager,
+                  bytes: &[u8],
+                  message_index: usize,
+                  message_offset: u64,
+                  options: &DecodeOptions) -> Result<BufrMessage, BufrKitError> {
+    let mut bd = BinaryDecoder {
+        table_group_manager: Some(table_group_manager),
+        br: BitReader::new(bytes),
+        message_index,
+        message_offset,
+        n_message_bits: bytes.len() as u64 * 8,
+        options,
+        last_section: 5,
+        header: None,
+        warnings: Vec::new(),
     };
-    let sections = bd.decode()?;
-    Ok(BufrMessage::new(sections))
+    bd.decode()
+}
+
+/// The table group that describes the data of a message with the given header
+pub fn table_group_id(header: &Section1Header) -> TableGroupId {
+    TableGroupId {
+        base_dir: "_definitions/tables".to_owned(),
+        master_table_number: header.master_table_number as isize,
+        centre_number: header.centre as isize,
+        sub_centre_number: header.subcentre as isize,
+        version_number: header.master_table_version as isize,
+    }
+}
+
+/// Read the headers of every message, i.e. sections 0 to 3, and seek past the data
+/// to check the stop signature, without loading any tables. Iteration ends after the first
+/// failure unless scanning is lenient, in which case it resumes after the start
+/// signature of the failed message.
+pub fn scan<R: BufRead + Seek>(r: R, options: &DecodeOptions) -> Scan<'_, R> {
+    Scan { r, options, message_index: 0, done: false }
+}
+
+pub struct Scan<'a, R> {
+    r: R,
+    options: &'a DecodeOptions,
+    message_index: usize,
+    done: bool,
+}
+
+impl<'a, R: BufRead + Seek> Iterator for Scan<'a, R> {
+    type Item = Result<MessageSummary, BufrKitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let start = match find_string("BUFR", &mut self.r).and_then(|_| Ok(self.r.stream_position()? - 4)) {
+            Ok(start) => start,
+            Err(BufrKitError::UnexpectedEof) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let message_index = self.message_index;
+        self.message_index += 1;
+        let result = self.scan_message(message_index, start);
+        let resume_at = match &result {
+            Ok(summary) => start + summary.length as u64,
+            Err(_) if self.options.lenient => start + 4,
+            Err(_) => {
+                self.done = true;
+                start
+            }
+        };
+        if let Err(e) = self.r.seek(SeekFrom::Start(resume_at)) {
+            self.done = true;
+            return Some(Err(e.into()));
+        }
+        Some(result.map_err(|e| e.at(Position { message_index, message_offset: start, section: None, bit_offset: 0 })))
+    }
+}
+
+impl<'a, R: BufRead + Seek> Scan<'a, R> {
+    /// Read sections 0 to 3 of the message whose start signature has just been read
+    fn scan_message(&mut self, message_index: usize, start: u64) -> Result<MessageSummary, BufrKitError> {
+        let mut bytes = b"BUFR".to_vec();
+        self.read_more(&mut bytes, 4)?;
+        let length = check_message_length(BitReader::new(&bytes[4..7]).read_u32(24)?, &self.options.limits)?;
+        // Leave editions that are not decoded for the decoder to reject
+        let edition = bytes[7];
+        if (2..=4).contains(&edition) {
+            let section_1 = self.read_section(&mut bytes, length)?;
+            // The flags follow the update sequence number, which edition 4 moved back by two bytes
+            let flags_at = if edition == 4 { 9 } else { 7 };
+            if bytes.get(section_1 + flags_at).is_some_and(|flags| flags & 0x80 != 0) {
+                self.read_section(&mut bytes, length)?;
+            }
+            self.read_section(&mut bytes, length)?;
+        }
+
+        let mut bd = BinaryDecoder {
+            table_group_manager: None,
+            br: BitReader::new(&bytes),
+            message_index,
+            message_offset: start,
+            n_message_bits: length as u64 * 8,
+            options: self.options,
+            last_section: 3,
+            header: None,
+            warnings: Vec::new(),
+        };
+        let message = bd.decode()?;
+        self.r.seek(SeekFrom::Start(start + length as u64 - 4))?;
+        let mut stop_signature = [0u8; 4];
+        read_bytes(&mut self.r, &mut stop_signature)?;
+        if &stop_signature != b"7777" {
+            return Err(BufrKitError::InvalidSection {
+                section: 5,
+                message: format!("Stop signature expected, found: {}", String::from_utf8_lossy(&stop_signature)),
+            });
+        }
+        let section_3 = message.section(3).ok_or_else(|| BufrKitError::InvalidSection {
+            section: 3,
+            message: "section not decoded".to_owned(),
+        })?;
+        Ok(MessageSummary {
+            index: message_index,
+            offset: start,
+            length,
+            header: message.header().clone(),
+            n_subsets: header_u32(section_3, "n_subsets")? as usize,
+            is_compressed: section_3.field_by_name("is_compressed")
+                .and_then(|f| f.try_get_bool())
+                .ok_or_else(|| missing_field(3, "is_compressed"))?,
+            unexpanded_descriptors: section_3.field_by_name("unexpanded_descriptors")
+                .and_then(|f| f.try_get_unexpanded_descriptors())
+                .ok_or_else(|| missing_field(3, "unexpanded_descriptors"))?
+                .to_vec(),
+        })
+    }
+
+    /// Read the section that follows the bytes, as far as the end of the message.
+    /// Returns where the section starts in the bytes.
+    fn read_section(&mut self, bytes: &mut Vec<u8>, length: usize) -> Result<usize, BufrKitError> {
+        let start = bytes.len();
+        self.read_more(bytes, 3)?;
+        let section_length = BitReader::new(&bytes[start..]).read_u32(24)? as usize;
+        self.read_more(bytes, section_length.saturating_sub(3).min(length.saturating_sub(bytes.len())))?;
+        Ok(start)
+    }
+
+    fn read_more(&mut self, bytes: &mut Vec<u8>, n: usize) -> Result<(), BufrKitError> {
+        let start = bytes.len();
+        bytes.resize(start + n, 0);
+        read_bytes(&mut self.r, &mut bytes[start..])
+    }
 }
 
-fn prepare(r: &mut dyn BufRead) -> Result<Vec<u8>, BufrKitError> {
+/// Total length of the message at the start of the bytes, checked to fit in them
+fn message_length(bytes: &[u8], limits: &DecodeLimits) -> Result<usize, BufrKitError> {
+    if bytes.len() < 8 {
+        return Err(BufrKitError::UnexpectedEof);
+    }
+    let length = check_message_length(BitReader::new(&bytes[4..7]).read_u32(24)?, limits)?;
+    if length > bytes.len() {
+        return Err(BufrKitError::UnexpectedEof);
+    }
+    Ok(length)
+}
+
+pub(crate) fn check_limit(limit: &'static str, value: usize, max: usize) -> Result<(), BufrKitError> {
+    if value > max {
+        Err(BufrKitError::LimitExceeded { limit, value, max })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_message_length(length: u32, limits: &DecodeLimits) -> Result<usize, BufrKitError> {
+    if length < 8 {
+        return Err(BufrKitError::InvalidSection {
+            section: 0,
+            message: format!("message length {} is shorter than section 0", length),
+        });
+    }
+    check_limit("message size", length as usize, limits.max_message_size)?;
+    Ok(length as usize)
+}
+
+/// Read the first message found in the reader. Errors after its start signature
+/// is found carry the position of the message.
+fn prepare(r: &mut dyn BufRead, limits: &DecodeLimits) -> Result<(usize, Vec<u8>), BufrKitError> {
     let start_signature = "BUFR".to_string();
     let pos = find_string(&start_signature, r)?;
-    let mut bytes = start_signature.as_bytes().to_vec();
+    let bytes = read_message(r, start_signature.as_bytes(), limits)
+        .map_err(|e| e.at(Position { message_offset: pos as u64, section: Some(0), ..Position::default() }))?;
+    Ok((pos, bytes))
+}
+
+fn read_message(r: &mut dyn BufRead, start_signature: &[u8], limits: &DecodeLimits) -> Result<Vec<u8>, BufrKitError> {
+    let mut bytes = start_signature.to_vec();
 
     let mut b = [0u8; 3];
     read_bytes(r, &mut b)?;
 
     let mut bit_reader = BitReader::new(&b[..]);
-    let length = bit_reader.read_u32(24)?;
+    let length = check_message_length(bit_reader.read_u32(24)?, limits)?;
     bytes.extend(&b);
 
-    let mut remaining = vec![0u8; (length - 7) as usize];
+    let mut remaining = vec![0u8; length - 7];
     read_bytes(r, remaining.borrow_mut())?;
     bytes.extend(remaining);
     Ok(bytes)
@@ -43,12 +543,22 @@ pub trait FieldReader {
     fn read_field_flag(&mut self, name: &str, nbits: u8) -> Result<Field, BufrKitError>;
     fn read_field_raw(&mut self, name: &str, nbits: usize) -> Result<Field, BufrKitError>;
     fn read_field_ued(&mut self, name: &str, n: usize) -> Result<Field, BufrKitError>;
-    fn read_field_payload(&mut self, name: &str, nbits: usize, template: &Template) -> Result<Field, BufrKitError>;
+    fn read_field_payload(&mut self, name: &str, table_group: &TableGroup, program: &Program,
+                          n_subsets: usize, compressed: bool) -> Result<Field, BufrKitError>;
 }
 
 struct BinaryDecoder<'a> {
-    table_group_manager: &'a TableGroupManager,
+    /// Only needed for section 4, so headers are decoded without one
+    table_group_manager: Option<&'a TableGroupManager>,
     br: BitReader<'a>,
+    message_index: usize,
+    message_offset: u64,
+    n_message_bits: u64,
+    options: &'a DecodeOptions,
+    /// Sections after this one are not decoded
+    last_section: u8,
+    header: Option<Section1Header>,
+    warnings: Vec<Warning>,
 }
 
 impl<'a> FieldReader for BinaryDecoder<'a> {
@@ -87,28 +597,152 @@ impl<'a> FieldReader for BinaryDecoder<'a> {
     fn read_field_ued(&mut self, name: &str, n: usize) -> Result<Field, BufrKitError> {
         let mut ids = Vec::new();
         for _ in 0..n {
-            ids.push((self.br.read_u32(2)? * 100_000
-                + self.br.read_u32(6)? * 1000
-                + self.br.read_u32(8)?) as isize);
+            ids.push(Fxy::new(self.br.read_u8(2)?, self.br.read_u8(6)?, self.br.read_u16(8)?)?);
         }
         Ok(Field::UED(FieldUED::new(name, ids)))
     }
 
-    fn read_field_payload(&mut self, name: &str, nbits: usize, template: &Template) -> Result<Field, BufrKitError> {
-        Err(BufrKitError { message: "".to_owned() })
+    fn read_field_payload(&mut self, name: &str, table_group: &TableGroup, program: &Program,
+                          n_subsets: usize, compressed: bool) -> Result<Field, BufrKitError> {
+        let position = self.position(4);
+        let mut reader = PayloadReader::new(
+            table_group, &mut self.br, n_subsets, compressed, self.options.limits, position);
+        if let Some(index) = self.options.subset {
+            reader.select_subset(index)?;
+        }
+        let projected;
+        let program = match &self.options.projection {
+            Some(ids) => {
+                reader.project(ids);
+                projected = program.project(ids, compressed);
+                &projected
+            }
+            None => program,
+        };
+        let (subsets, warnings) = decode_with_program(program, reader)?;
+        self.warnings.extend(warnings);
+        Ok(Field::PAYLOAD(FieldPayload::with_first_subset(name, self.options.subset.unwrap_or(0), subsets)))
     }
 }
 
 impl<'a> BinaryDecoder<'a> {
-    fn decode(&mut self) -> Result<Vec<BufrSection>, BufrKitError> {
+    fn decode(&mut self) -> Result<BufrMessage, BufrKitError> {
+        type DecodeSection<'b> = fn(&mut BinaryDecoder<'b>, &mut Vec<BufrSection>) -> Result<(), BufrKitError>;
+        let decode_sections: [DecodeSection<'a>; 6] = [
+            BinaryDecoder::decode_section_0,
+            BinaryDecoder::decode_section_1,
+            BinaryDecoder::decode_section_2,
+            BinaryDecoder::decode_section_3,
+            BinaryDecoder::decode_section_4,
+            BinaryDecoder::decode_section_5,
+        ];
         let mut sections = Vec::new();
-        self.decode_section_0(&mut sections)?;
-        self.decode_section_1(&mut sections)?;
-        self.decode_section_2(&mut sections)?;
-        self.decode_section_3(&mut sections)?;
-        self.decode_section_4(&mut sections)?;
-        self.decode_section_5(&mut sections)?;
-        Ok(sections)
+        for (i, decode_section) in decode_sections.iter().enumerate().take(self.last_section as usize + 1) {
+            let start = self.br.position();
+            decode_section(self, &mut sections)
+                .and_then(|_| self.check_section_end(i as u8, start, &sections))
+                .map_err(|e| e.at(self.position(i as u8)))?;
+        }
+        let header = self.header.take().ok_or_else(|| BufrKitError::InvalidSection {
+            section: 1,
+            message: "section not decoded".to_owned(),
+        })?;
+        Ok(BufrMessage::new(header, sections, std::mem::take(&mut self.warnings)))
+    }
+
+    /// Start of section 5, which is always the last 4 bytes of the message
+    fn section_5_start(&self) -> u64 {
+        self.n_message_bits - 32
+    }
+
+    /// Check that a section ended where its length says and skip any bytes left in
+    /// it, e.g. local use bytes in section 1 or padding in section 3
+    fn check_section_end(&mut self, section: u8, start: u64, sections: &[BufrSection]) -> Result<(), BufrKitError> {
+        if section == 0 || section == 5 {
+            return Ok(());
+        }
+        let n_bits = required_section(sections, section)?.try_length()
+            .ok_or_else(|| missing_field(section, "section_length"))? as u64 * 8;
+        let end = start + n_bits;
+        if end > self.section_5_start() {
+            return Err(BufrKitError::InvalidSection {
+                section,
+                message: format!("section length {} extends past section 5", n_bits / 8),
+            });
+        }
+        let consumed = self.br.position() - start;
+        if consumed > n_bits {
+            self.problem(section, WarningKind::SectionLength,
+                         format!("section length {} is shorter than its {} bytes of fields", n_bits / 8, consumed / 8))?;
+        } else if section == 4 && self.options.subset.is_some() {
+            // Subsets after the selected one are left unread
+            self.br.skip(n_bits - consumed)?;
+        } else if section == 3 || section == 4 {
+            self.skip_padding(section, n_bits - consumed)?;
+        } else {
+            self.br.skip(n_bits - consumed)?;
+        }
+        if section == 4 && self.br.position() < self.section_5_start() {
+            let gap = self.section_5_start() - self.br.position();
+            self.problem(section, WarningKind::SectionLength,
+                         format!("{} bytes between the end of section 4 and section 5", gap / 8))?;
+            self.br.skip(gap)?;
+        }
+        Ok(())
+    }
+
+    /// Fail on an inconsistency in strict mode, otherwise record it as a warning
+    fn problem(&mut self, section: u8, kind: WarningKind, message: String) -> Result<(), BufrKitError> {
+        if self.options.lenient {
+            self.warn(section, kind, message);
+            Ok(())
+        } else {
+            Err(BufrKitError::InvalidSection { section, message })
+        }
+    }
+
+    fn warn(&mut self, section: u8, kind: WarningKind, message: String) {
+        let position = self.position(section);
+        self.warnings.push(Warning { kind, descriptor: None, position, message });
+    }
+
+    fn read_reserved_bits(&mut self, section: u8, name: &str, nbits: u8) -> Result<Field, BufrKitError> {
+        let field = self.read_field_flag(name, nbits)?;
+        if let Some(SimpleData::FLAG(v, _)) = field.try_get_simple_data() {
+            if *v != 0 {
+                self.problem(section, WarningKind::ReservedBits, format!("{} is {:#b}, expected 0", name, v))?;
+            }
+        }
+        Ok(field)
+    }
+
+    /// Skip padding at the end of a section, which rounds it up to whole bytes,
+    /// or an even number of them in older editions, and should be zero
+    fn skip_padding(&mut self, section: u8, nbits: u64) -> Result<(), BufrKitError> {
+        if nbits >= 16 {
+            self.warn(section, WarningKind::Padding, format!("{} bits of padding, expected less than 16", nbits));
+            return Ok(self.br.skip(nbits)?);
+        }
+        if nbits > 0 && self.br.read_u16(nbits as u8)? != 0 {
+            self.warn(section, WarningKind::Padding, "padding is not zero".to_owned());
+        }
+        Ok(())
+    }
+
+    fn position(&self, section: u8) -> Position {
+        Position {
+            message_index: self.message_index,
+            message_offset: self.message_offset,
+            section: Some(section),
+            bit_offset: self.br.position(),
+        }
+    }
+
+    fn required_header(&self) -> Result<&Section1Header, BufrKitError> {
+        self.header.as_ref().ok_or_else(|| BufrKitError::InvalidSection {
+            section: 1,
+            message: "section not decoded".to_owned(),
+        })
     }
 
     fn decode_section_0(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
@@ -117,43 +751,26 @@ impl<'a> BinaryDecoder<'a> {
             self.read_field_u32("length", 24)?,
         );
         let field = self.read_field_u32("edition", 8)?;
-        return if field.get_u32() != 4 {
-            Err(BufrKitError {
-                message: format!("Only support BUFR edition 4, got {}", field.get_u32())
-            })
+        let edition = required_u32(0, &field)?;
+        if !(2..=4).contains(&edition) {
+            Err(BufrKitError::Unsupported(format!("Only support BUFR editions 2 to 4, got {}", edition)))
         } else {
             fields.push(field);
             sections.push(BufrSection::new(0, fields));
             Ok(())
-        };
+        }
     }
 
     fn decode_section_1(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
-        let edition = sections[0].field_by_name("edition").unwrap().get_u32();
-        match edition {
-            1 => Ok(sections.push(BufrSection::new(1, vec!(
-                self.read_field_u32("originating_centre", 16)?,
-                self.read_field_u32("update_sequence_number", 8)?,
-                self.read_field_bool("is_section2_presents")?,
-                self.read_field_flag("flag_bits", 7)?,
-                self.read_field_u32("data_category", 8)?,
-                self.read_field_u32("data_local_subcategory", 8)?,
-                self.read_field_u32("master_table_version", 8)?,
-                self.read_field_u32("local_table_version", 8)?,
-                self.read_field_u32("year", 8)?,
-                self.read_field_u32("month", 8)?,
-                self.read_field_u32("day", 8)?,
-                self.read_field_u32("hour", 8)?,
-                self.read_field_u32("minute", 8)?,
-                self.read_field_u32("second", 8)?,
-            )))),
-            2 => Ok(sections.push(BufrSection::new(1, vec!(
+        let edition = header_u32(required_section(sections, 0)?, "edition")?;
+        let fields = match edition {
+            2 => vec!(
                 self.read_field_u32("section_length", 24)?,
                 self.read_field_u32("master_table_number", 8)?,
                 self.read_field_u32("originating_centre", 16)?,
                 self.read_field_u32("update_sequence_number", 8)?,
                 self.read_field_bool("is_section2_presents")?,
-                self.read_field_flag("flag_bits", 7)?,
+                self.read_reserved_bits(1, "flag_bits", 7)?,
                 self.read_field_u32("data_category", 8)?,
                 self.read_field_u32("data_local_subcategory", 8)?,
                 self.read_field_u32("master_table_version", 8)?,
@@ -163,18 +780,16 @@ impl<'a> BinaryDecoder<'a> {
                 self.read_field_u32("day", 8)?,
                 self.read_field_u32("hour", 8)?,
                 self.read_field_u32("minute", 8)?,
-                self.read_field_u32("second", 8)?,
-            )))),
-            3 => Ok(sections.push(BufrSection::new(1, vec!(
+            ),
+            3 => vec!(
                 self.read_field_u32("section_length", 24)?,
                 self.read_field_u32("master_table_number", 8)?,
                 self.read_field_u32("originating_subcentre", 8)?,
                 self.read_field_u32("originating_centre", 8)?,
                 self.read_field_u32("update_sequence_number", 8)?,
                 self.read_field_bool("is_section2_presents")?,
-                self.read_field_flag("flag_bits", 7)?,
+                self.read_reserved_bits(1, "flag_bits", 7)?,
                 self.read_field_u32("data_category", 8)?,
-                self.read_field_u32("data_i18n_subcategory", 8)?,
                 self.read_field_u32("data_local_subcategory", 8)?,
                 self.read_field_u32("master_table_version", 8)?,
                 self.read_field_u32("local_table_version", 8)?,
@@ -183,16 +798,15 @@ impl<'a> BinaryDecoder<'a> {
                 self.read_field_u32("day", 8)?,
                 self.read_field_u32("hour", 8)?,
                 self.read_field_u32("minute", 8)?,
-                self.read_field_u32("second", 8)?,
-            )))),
-            4 => Ok(sections.push(BufrSection::new(1, vec!(
+            ),
+            4 => vec!(
                 self.read_field_u32("section_length", 24)?,
                 self.read_field_u32("master_table_number", 8)?,
                 self.read_field_u32("originating_centre", 16)?,
                 self.read_field_u32("originating_subcentre", 16)?,
                 self.read_field_u32("update_sequence_number", 8)?,
                 self.read_field_bool("is_section2_presents")?,
-                self.read_field_flag("flag_bits", 7)?,
+                self.read_reserved_bits(1, "flag_bits", 7)?,
                 self.read_field_u32("data_category", 8)?,
                 self.read_field_u32("data_i18n_subcategory", 8)?,
                 self.read_field_u32("data_local_subcategory", 8)?,
@@ -204,20 +818,22 @@ impl<'a> BinaryDecoder<'a> {
                 self.read_field_u32("hour", 8)?,
                 self.read_field_u32("minute", 8)?,
                 self.read_field_u32("second", 8)?,
-            )))),
-            _ => Err(BufrKitError {
-                message: format!("Unknown BUFR edition number: {}", edition)
-            })
-        }
+            ),
+            _ => return Err(BufrKitError::Unsupported(format!("Unknown BUFR edition number: {}", edition)))
+        };
+        let section = BufrSection::new(1, fields);
+        self.header = Some(Section1Header::from_section(edition as u8, &section)?);
+        sections.push(section);
+        Ok(())
     }
 
     fn decode_section_2(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
-        if sections[1].field_by_name("is_section2_presents").unwrap().get_bool() {
+        if self.required_header()?.has_section2 {
             let field = self.read_field_u32("section_length", 24)?;
-            let n_local_bits = ((field.get_u32() - 4) * 8) as usize;
+            let n_local_bits = body_bits(2, &field, 4)?;
             sections.push(BufrSection::new(2, vec!(
                 field,
-                self.read_field_flag("reserved_bits", 8)?,
+                self.read_reserved_bits(2, "reserved_bits", 8)?,
                 self.read_field_raw("local_bits", n_local_bits)?,
             )));
         } else {
@@ -228,74 +844,145 @@ impl<'a> BinaryDecoder<'a> {
 
     fn decode_section_3(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
         let field = self.read_field_u32("section_length", 24)?;
-        let mut n_descriptors = ((field.get_u32() - 7) / 2) as usize;
+        let n_descriptors = body_bits(3, &field, 7)? / 16;
         let mut fields = vec!(field);
-        fields.push(self.read_field_flag("reserved_bits", 8)?);
+        fields.push(self.read_reserved_bits(3, "reserved_bits", 8)?);
         let field = self.read_field_u32("n_subsets", 16)?;
-        let n_subsets = field.get_u32();
+        check_limit("number of subsets", required_u32(3, &field)? as usize, self.options.limits.max_subsets)?;
         fields.push(field);
         fields.push(self.read_field_bool("is_observation")?);
         fields.push(self.read_field_bool("is_compressed")?);
-        fields.push(self.read_field_flag("reserved_bits", 6)?);
+        fields.push(self.read_reserved_bits(3, "reserved_bits", 6)?);
 
         fields.push(self.read_field_ued("unexpanded_descriptors", n_descriptors)?);
         sections.push(BufrSection::new(3, fields));
-        // TODO: consume padding data if any
         Ok(())
     }
 
     fn decode_section_4(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
         let field = self.read_field_u32("section_length", 24)?;
-        let n_data_bits = ((field.get_u32() - 4) * 8) as usize;
+        let n_data_bits = body_bits(4, &field, 4)?;
         let mut fields = vec!(field);
-        fields.push(self.read_field_flag("reserved_bits", 8)?);
-
-        let section_1 = sections.get(1).unwrap();
-        let table_group_id = TableGroupId {
-            base_dir: "_definitions/tables".to_owned(),
-            master_table_number: section_1.field_by_name("master_table_number").unwrap().get_u32() as isize,
-            centre_number: section_1.field_by_name("originating_centre").unwrap().get_u32() as isize,
-            sub_centre_number: section_1.field_by_name("originating_subcentre").map_or_else(|| 0, |f| f.get_u32()) as isize,
-            version_number: section_1.field_by_name("master_table_version").unwrap().get_u32() as isize,
-        };
-        let table_group = self.table_group_manager.get_table_group(&table_group_id)?;
-        let section_3 = sections.get(3).unwrap();
+        fields.push(self.read_reserved_bits(4, "reserved_bits", 8)?);
+
+        let table_group_manager = self.table_group_manager.ok_or_else(
+            || BufrKitError::Other("section 4 cannot be decoded without a table group manager".to_owned()))?;
+        let header = self.required_header()?;
+        let table_group = table_group_manager.get_table_group(&table_group_id(header))?;
+        let section_3 = required_section(sections, 3)?;
+        let n_subsets = header_u32(section_3, "n_subsets")? as usize;
+        let compressed = section_3.field_by_name("is_compressed")
+            .and_then(|f| f.try_get_bool())
+            .ok_or_else(|| missing_field(3, "is_compressed"))?;
         let unexpanded_descriptors = section_3
             .field_by_name("unexpanded_descriptors")
-            .unwrap()
-            .get_unexpanded_descriptors();
-        let mut template = Template::new(&table_group, unexpanded_descriptors)?;
+            .and_then(|f| f.try_get_unexpanded_descriptors())
+            .ok_or_else(|| missing_field(3, "unexpanded_descriptors"))?;
+        let (template, program) = table_group_manager.get_compiled(
+            &table_group, unexpanded_descriptors, self.options.limits.max_expansion_depth)?;
 
-        fields.push(self.read_field_raw("template_data", n_data_bits)?);
+        // Operators that are not supported change how the data is laid out, so the data
+        // is kept as it is rather than decoded wrongly
+        if let Some(id) = unsupported_operator(&template) {
+            let position = self.position(4);
+            self.warnings.push(Warning {
+                kind: WarningKind::UnsupportedOperator,
+                descriptor: Some(id),
+                position,
+                message: "template data is not decoded".to_owned(),
+            });
+            fields.push(self.read_field_raw("template_data", n_data_bits)?);
+        } else {
+            fields.push(self.read_field_payload("template_data", &table_group, &program, n_subsets, compressed)?);
+        }
         sections.push(BufrSection::new(4, fields));
         Ok(())
     }
 
     fn decode_section_5(&mut self, sections: &mut Vec<BufrSection>) -> Result<(), BufrKitError> {
         let field = self.read_field_bytes("stop_signature", 4)?;
-        return if field.get_bytes() != "7777" {
-            Err(BufrKitError {
-                message: format!("Stop signature expected, found: {}", field.get_bytes())
+        let stop_signature = field.try_get_bytes().ok_or_else(|| missing_field(5, "stop_signature"))?;
+        if stop_signature != "7777" {
+            Err(BufrKitError::InvalidSection {
+                section: 5,
+                message: format!("Stop signature expected, found: {}", stop_signature),
             })
         } else {
             sections.push(BufrSection::new(5, vec!(field)));
             Ok(())
-        };
+        }
     }
 }
 
-pub fn find_string(s: &str, r: &mut dyn BufRead) -> Result<usize, BufrKitError> {
-    let states = s.as_bytes();
-    let mut i = 0usize;
-    let mut b = [0u8];
-    let mut p = 0usize;
+fn required_section(sections: &[BufrSection], index: u8) -> Result<&BufrSection, BufrKitError> {
+    sections.get(index as usize).ok_or_else(|| BufrKitError::InvalidSection {
+        section: index,
+        message: "section not decoded".to_owned(),
+    })
+}
+
+fn missing_field(section: u8, name: &str) -> BufrKitError {
+    BufrKitError::InvalidSection { section, message: format!("{}: field not found", name) }
+}
 
-    while i < states.len() {
-        read_bytes(r, &mut b)?;
-        i = if b[0] == states[i] { i + 1 } else { 0 };
-        p += 1;
+fn required_u32(section: u8, field: &Field) -> Result<u32, BufrKitError> {
+    field.try_get_u32().ok_or_else(|| missing_field(section, field.name()))
+}
+
+fn header_u32(section: &BufrSection, name: &str) -> Result<u32, BufrKitError> {
+    section.field_by_name(name)
+        .and_then(|f| f.try_get_u32())
+        .ok_or_else(|| missing_field(section.index(), name))
+}
+
+/// Number of bits in a section after its first `n_header_bytes`, given its length field
+fn body_bits(section: u8, length: &Field, n_header_bytes: u32) -> Result<usize, BufrKitError> {
+    let section_length = required_u32(section, length)?;
+    let n_bytes = section_length.checked_sub(n_header_bytes).ok_or_else(|| BufrKitError::InvalidSection {
+        section,
+        message: format!("section length {} is shorter than {} bytes", section_length, n_header_bytes),
+    })?;
+    Ok(n_bytes as usize * 8)
+}
+
+lazy_static! {
+    static ref START_SIGNATURE: memmem::Finder<'static> = memmem::Finder::new(b"BUFR");
+}
+
+/// Offset of the next start signature in the bytes
+fn find_start_signature(haystack: &[u8]) -> Option<usize> {
+    START_SIGNATURE.find(haystack)
+}
+
+/// Find the string in the reader, a buffer at a time, and consume the reader up to
+/// the end of it. Returns the number of bytes before the string.
+pub fn find_string(s: &str, r: &mut dyn BufRead) -> Result<usize, BufrKitError> {
+    let needle = s.as_bytes();
+    let finder = memmem::Finder::new(needle);
+    // The end of the bytes consumed so far, for strings split between two buffers
+    let mut tail: Vec<u8> = Vec::new();
+    let mut n_consumed = 0usize;
+    loop {
+        let buf = r.fill_buf()?;
+        if buf.is_empty() {
+            return Err(BufrKitError::UnexpectedEof);
+        }
+        let mut joined = tail.clone();
+        joined.extend_from_slice(&buf[..buf.len().min(needle.len().saturating_sub(1))]);
+        if let Some(pos) = finder.find(&joined) {
+            r.consume(pos + needle.len() - tail.len());
+            return Ok(n_consumed - tail.len() + pos);
+        }
+        if let Some(pos) = finder.find(buf) {
+            r.consume(pos + needle.len());
+            return Ok(n_consumed + pos);
+        }
+        let n = buf.len();
+        tail.extend_from_slice(buf);
+        tail.drain(..tail.len().saturating_sub(needle.len().saturating_sub(1)));
+        r.consume(n);
+        n_consumed += n;
     }
-    Ok(p - states.len())
 }
 
 fn read_bytes(r: &mut dyn BufRead, b: &mut [u8]) -> Result<(), BufrKitError> {
@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::path::PathBuf;
+use bitreader::BitReaderError;
+use crate::table::descriptor::Fxy;
+
+/// Where in the input decoding failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    /// Index of the message in the input, starting from 0
+    pub message_index: usize,
+    /// Offset in bytes of the start of the message in the input
+    pub message_offset: u64,
+    /// Index of the section being decoded, if decoding had reached one
+    pub section: Option<u8>,
+    /// Offset in bits from the start of the message
+    pub bit_offset: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message {} at byte {}", self.message_index, self.message_offset)?;
+        if let Some(section) = self.section {
+            write!(f, ", section {}", section)?;
+        }
+        write!(f, ", bit offset {}", self.bit_offset)
+    }
+}
+
+#[derive(Debug)]
+pub enum BufrKitError {
+    Io(io::Error),
+    /// Input ended before the data being read was complete
+    UnexpectedEof,
+    TableNotFound { path: PathBuf, source: io::Error },
+    InvalidTable { path: PathBuf, source: Box<dyn Error + Send + Sync> },
+    DescriptorNotFound(Fxy),
+    InvalidDescriptor(String),
+    CodeNotFound { id: Fxy, value: isize },
+    MetadataNotFound(String),
+    InvalidSection { section: u8, message: String },
+    /// Input asked for more than a configured decoding limit allows
+    LimitExceeded { limit: &'static str, value: usize, max: usize },
+    /// A subset was asked for that the message does not have
+    SubsetOutOfRange { index: usize, n_subsets: usize },
+    Unsupported(String),
+    Json(serde_json::Error),
+    Regex(regex::Error),
+    ParseInt(ParseIntError),
+    Other(String),
+    /// An error raised while decoding, together with where it happened
+    Decode { position: Position, source: Box<BufrKitError> },
+}
+
+impl BufrKitError {
+    /// Attach the decoding position. Errors that already carry one keep it.
+    pub fn at(self, position: Position) -> Self {
+        match self {
+            BufrKitError::Decode { .. } => self,
+            _ => BufrKitError::Decode { position, source: Box::new(self) },
+        }
+    }
+
+    pub fn position(&self) -> Option<&Position> {
+        match self {
+            BufrKitError::Decode { position, .. } => Some(position),
+            _ => None,
+        }
+    }
+
+    /// The error without any decoding position attached
+    pub fn kind(&self) -> &BufrKitError {
+        match self {
+            BufrKitError::Decode { source, .. } => source.kind(),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for BufrKitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufrKitError::Io(e) => write!(f, "{}", e),
+            BufrKitError::UnexpectedEof => write!(f, "unexpected end of input"),
+            BufrKitError::TableNotFound { path, source } =>
+                write!(f, "{}: table not found: {}", path.display(), source),
+            BufrKitError::InvalidTable { path, source } =>
+                write!(f, "{}: invalid table: {}", path.display(), source),
+            BufrKitError::DescriptorNotFound(id) => write!(f, "{} not found", id),
+            BufrKitError::InvalidDescriptor(message) => write!(f, "{}", message),
+            BufrKitError::CodeNotFound { id, value } =>
+                write!(f, "Entry not found for {} with value {}", id, value),
+            BufrKitError::MetadataNotFound(message) => write!(f, "{}", message),
+            BufrKitError::InvalidSection { section, message } =>
+                write!(f, "section {}: {}", section, message),
+            BufrKitError::LimitExceeded { limit, value, max } =>
+                write!(f, "{} {} exceeds the limit of {}", limit, value, max),
+            BufrKitError::SubsetOutOfRange { index, n_subsets } =>
+                write!(f, "subset {} is out of range, the message has {} subset(s)", index, n_subsets),
+            BufrKitError::Unsupported(message) => write!(f, "{}", message),
+            BufrKitError::Json(e) => write!(f, "{}", e),
+            BufrKitError::Regex(e) => write!(f, "{}", e),
+            BufrKitError::ParseInt(e) => write!(f, "{}", e),
+            BufrKitError::Other(message) => write!(f, "{}", message),
+            BufrKitError::Decode { position, source } => write!(f, "{}: {}", position, source),
+        }
+    }
+}
+
+impl Error for BufrKitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BufrKitError::Io(e) => Some(e),
+            BufrKitError::TableNotFound { source, .. } => Some(source),
+            BufrKitError::InvalidTable { source, .. } => Some(source.as_ref()),
+            BufrKitError::Json(e) => Some(e),
+            BufrKitError::Regex(e) => Some(e),
+            BufrKitError::ParseInt(e) => Some(e),
+            BufrKitError::Decode { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BufrKitError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            BufrKitError::UnexpectedEof
+        } else {
+            BufrKitError::Io(e)
+        }
+    }
+}
+
+impl From<BitReaderError> for BufrKitError {
+    fn from(e: BitReaderError) -> Self {
+        match e {
+            BitReaderError::NotEnoughData { .. } => BufrKitError::UnexpectedEof,
+            _ => BufrKitError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<ParseIntError> for BufrKitError {
+    fn from(e: ParseIntError) -> Self {
+        BufrKitError::ParseInt(e)
+    }
+}
+
+impl From<regex::Error> for BufrKitError {
+    fn from(e: regex::Error) -> Self {
+        BufrKitError::Regex(e)
+    }
+}
+
+impl From<serde_json::Error> for BufrKitError {
+    fn from(e: serde_json::Error) -> Self {
+        BufrKitError::Json(e)
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::bufr::{MessageSummary, Timestamp};
+use crate::decoder::{table_group_id, DecodeLimits};
+use crate::input::Compression;
+use crate::table::descriptor::Fxy;
+use crate::table::table::TableGroupManager;
+use crate::table::template::{Children, Template};
+use crate::BufrKitError;
+
+/// Changes whenever the layout of the index file changes
+pub const INDEX_VERSION: u32 = 4;
+
+/// Messages of an archive, as found by `decoder::scan`, saved next to the archive so
+/// that they can be looked up without scanning it again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    version: u32,
+    source: SourceInfo,
+    /// Every descriptor of each expanded template, by template hash, as expanded with the
+    /// tables of the first message that uses the template
+    descriptors: BTreeMap<u64, BTreeSet<Fxy>>,
+    entries: Vec<IndexEntry>,
+}
+
+/// The archive as it was when it was indexed, to tell when the index is out of date
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    /// Length of the file as stored, compressed or not
+    pub length: u64,
+    /// Last modification time, if the file system keeps one
+    pub modified: Option<SystemTime>,
+    /// How the file is compressed. Message offsets are positions in the decompressed
+    /// data when it is, and in the file itself otherwise.
+    pub compression: Option<Compression>,
+}
+
+impl SourceInfo {
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self, BufrKitError> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let compression = if metadata.is_file() {
+            Compression::detect(BufReader::new(file).fill_buf()?)
+        } else {
+            None
+        };
+        Ok(SourceInfo { length: metadata.len(), modified: metadata.modified().ok(), compression })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub summary: MessageSummary,
+    /// Hash of the unexpanded descriptors, equal for messages that share a template
+    pub template_hash: u64,
+    /// Why the template could not be expanded, e.g. because its tables are missing,
+    /// in which case the index has no descriptors for it
+    pub expansion_error: Option<String>,
+}
+
+impl fmt::Display for IndexEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}\t{}", self.template_hash, self.summary)
+    }
+}
+
+/// Which messages to find. Messages must match every condition that is set.
+#[derive(Debug, Clone, Default)]
+pub struct IndexQuery {
+    pub centre: Option<u16>,
+    pub data_category: Option<u8>,
+    /// Earliest time of the messages, inclusive
+    pub from: Option<Timestamp>,
+    /// Latest time of the messages, exclusive
+    pub to: Option<Timestamp>,
+    /// A descriptor used by the messages, either unexpanded or in their expanded templates
+    pub descriptor: Option<Fxy>,
+}
+
+impl IndexQuery {
+    /// Whether the entry matches, given the descriptors of its expanded template if known
+    pub fn matches(&self, entry: &IndexEntry, descriptors: Option<&BTreeSet<Fxy>>) -> bool {
+        let header = &entry.summary.header;
+        self.centre.is_none_or(|centre| centre == header.centre)
+            && self.data_category.is_none_or(|category| category == header.data_category)
+            && self.from.is_none_or(|from| header.timestamp >= from)
+            && self.to.is_none_or(|to| header.timestamp < to)
+            && self.descriptor.is_none_or(|id| {
+                entry.summary.unexpanded_descriptors.contains(&id)
+                    || descriptors.is_some_and(|descriptors| descriptors.contains(&id))
+            })
+    }
+}
+
+impl Index {
+    pub fn new(source: SourceInfo) -> Self {
+        Index { version: INDEX_VERSION, source, descriptors: BTreeMap::new(), entries: Vec::new() }
+    }
+
+    /// Where the index of an archive is kept
+    pub fn path_for<P: AsRef<Path>>(archive: P) -> PathBuf {
+        let mut path = archive.as_ref().as_os_str().to_owned();
+        path.push(".idx");
+        PathBuf::from(path)
+    }
+
+    /// Add a scanned message. Its template is expanded to record its descriptors unless
+    /// an earlier message shares it, otherwise the entry records why it could not be
+    /// expanded, e.g. when the tables of the message are missing. Returns the added entry.
+    pub fn push(&mut self, summary: MessageSummary, table_group_manager: &TableGroupManager,
+                limits: &DecodeLimits) -> &IndexEntry {
+        let template_hash = template_hash(&summary.unexpanded_descriptors);
+        let mut expansion_error = None;
+        if let Entry::Vacant(vacant) = self.descriptors.entry(template_hash) {
+            match table_group_manager.get_table_group(&table_group_id(&summary.header))
+                .and_then(|table_group| table_group_manager.get_template(
+                    &table_group, &summary.unexpanded_descriptors, limits.max_expansion_depth)) {
+                Ok(template) => {
+                    vacant.insert(descriptors_of(&template));
+                }
+                Err(e) => expansion_error = Some(e.to_string()),
+            }
+        }
+        self.entries.push(IndexEntry { summary, template_hash, expansion_error });
+        self.entries.last().expect("entry has been pushed")
+    }
+
+    /// Every descriptor of the expanded template of the entry, if it could be expanded
+    pub fn descriptors_of(&self, entry: &IndexEntry) -> Option<&BTreeSet<Fxy>> {
+        self.descriptors.get(&entry.template_hash)
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn source(&self) -> &SourceInfo {
+        &self.source
+    }
+
+    pub fn query<'a>(&'a self, query: &'a IndexQuery) -> impl Iterator<Item=&'a IndexEntry> + 'a {
+        self.entries.iter().filter(move |entry| query.matches(entry, self.descriptors_of(entry)))
+    }
+
+    pub fn read<R: Read>(r: R) -> Result<Self, BufrKitError> {
+        let index: Index = serde_json::from_reader(r)?;
+        if index.version != INDEX_VERSION {
+            return Err(BufrKitError::Unsupported(format!(
+                "index version {} is not supported, expected {}", index.version, INDEX_VERSION)));
+        }
+        Ok(index)
+    }
+
+    pub fn write<W: Write>(&self, w: W) -> Result<(), BufrKitError> {
+        Ok(serde_json::to_writer(w, self)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BufrKitError> {
+        Index::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), BufrKitError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write(&mut w)?;
+        Ok(w.flush()?)
+    }
+}
+
+/// FNV-1a of the descriptor IDs, which unlike `DefaultHasher` stays the same across
+/// builds so that saved hashes can be compared
+pub fn template_hash(ids: &[Fxy]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &id in ids {
+        for byte in u32::from(id).to_be_bytes().iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+fn descriptors_of(template: &Template) -> BTreeSet<Fxy> {
+    let mut descriptors = BTreeSet::new();
+    collect_descriptors(template.root().children(), &mut descriptors);
+    descriptors
+}
+
+fn collect_descriptors(children: Children<'_>, descriptors: &mut BTreeSet<Fxy>) {
+    for node in children.iter() {
+        descriptors.insert(node.descriptor().id());
+        collect_descriptors(node.children(), descriptors);
+    }
+}
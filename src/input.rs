@@ -0,0 +1,272 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use bzip2::read::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use memmap2::Mmap;
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+use crate::BufrKitError;
+
+/// An input file, memory mapped where possible so that messages are decoded straight
+/// from the mapping without copying the file. Compressed files are decompressed as they
+/// are read instead, so that they are never held in memory as a whole.
+pub enum Input {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+    Decompressed(Box<dyn BufRead>),
+}
+
+impl Input {
+    /// Read the file into memory, or stream it if it is compressed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Input, BufrKitError> {
+        Input::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Map the file, or read it when it cannot be mapped, e.g. when it is empty or
+    /// not a regular file. Compressed files are streamed.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while the input is in use, which
+    /// would change the mapped bytes or make them inaccessible.
+    pub unsafe fn map<P: AsRef<Path>>(path: P) -> Result<Input, BufrKitError> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if metadata.is_file() && metadata.len() > 0 {
+            if let Ok(mmap) = Mmap::map(&file) {
+                if Compression::detect(&mmap).is_none() {
+                    return Ok(Input::Mapped(mmap));
+                }
+            }
+        }
+        Input::read(BufReader::new(file))
+    }
+
+    pub fn stdin() -> Result<Input, BufrKitError> {
+        Input::read(io::stdin().lock())
+    }
+
+    /// Read everything, or stream it decompressed if it is compressed
+    pub fn read<R: BufRead + 'static>(mut r: R) -> Result<Input, BufrKitError> {
+        if Compression::detect(r.fill_buf()?).is_some() {
+            return Ok(Input::Decompressed(decompressed(r)?));
+        }
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        Ok(Input::Owned(bytes))
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Input::Mapped(_))
+    }
+
+    /// All the bytes, unless the input is streamed
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Input::Mapped(mmap) => Some(mmap),
+            Input::Owned(bytes) => Some(bytes),
+            Input::Decompressed(_) => None,
+        }
+    }
+}
+
+/// A stream made seekable, e.g. a decompressed one, to be read by `decoder::scan` or
+/// `decoder::decode_stream`. Seeking forward reads past the bytes in between, and
+/// seeking back only reaches as far as the last `history` bytes read.
+pub struct Rewind<R> {
+    r: R,
+    /// Bytes read from the stream, of which at least the last `history` are kept
+    buf: Vec<u8>,
+    /// Stream offset of the first byte kept
+    buf_offset: u64,
+    pos: u64,
+    history: usize,
+}
+
+impl<R: BufRead> Rewind<R> {
+    pub fn new(r: R, history: usize) -> Self {
+        Rewind { r, buf: Vec::new(), buf_offset: 0, pos: 0, history }
+    }
+
+    /// Stream offset of the end of the bytes read so far
+    fn end(&self) -> u64 {
+        self.buf_offset + self.buf.len() as u64
+    }
+}
+
+impl<R: BufRead> Read for Rewind<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Rewind<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.end() {
+            // Drop what is beyond the history only once it has grown as large, so
+            // that each byte is moved at most once
+            if self.buf.len() > 2 * self.history {
+                let n = self.buf.len() - self.history;
+                self.buf.drain(..n);
+                self.buf_offset += n as u64;
+            }
+            let data = self.r.fill_buf()?;
+            let n = data.len();
+            self.buf.extend_from_slice(data);
+            self.r.consume(n);
+        }
+        Ok(&self.buf[(self.pos - self.buf_offset) as usize..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt as u64).min(self.end());
+    }
+}
+
+impl<R: BufRead> Seek for Rewind<R> {
+    /// Seeking past the end of the stream stops at its end
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "cannot seek from the end of a stream")),
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek before the start of a stream"))?;
+        if target < self.buf_offset {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                      format!("cannot seek back to {}, which is no longer kept", target)));
+        }
+        while self.end() < target {
+            self.pos = self.end();
+            if self.fill_buf()?.is_empty() {
+                break;
+            }
+        }
+        self.pos = target.min(self.end());
+        Ok(self.pos)
+    }
+}
+
+/// The files named on the command line, in order. `-` stands for stdin, directories are
+/// walked recursively in name order, skipping index files, and names that do not exist
+/// are matched as glob patterns. Each name that cannot be expanded gives an error, and
+/// files are not opened, so a file that does not exist fails when it is.
+pub fn expand_inputs<S: AsRef<str>>(names: &[S]) -> Vec<Result<PathBuf, BufrKitError>> {
+    let mut paths = Vec::new();
+    for name in names.iter().map(|name| name.as_ref()) {
+        let path = Path::new(name);
+        if name == "-" || path.exists() || !name.contains(['*', '?', '[']) {
+            walk(path.to_path_buf(), &mut paths);
+            continue;
+        }
+        let matches = match glob::glob(name) {
+            Ok(matches) => matches,
+            Err(e) => {
+                paths.push(Err(BufrKitError::Other(format!("{}: invalid pattern: {}", name, e))));
+                continue;
+            }
+        };
+        let n_paths = paths.len();
+        for result in matches {
+            match result {
+                Ok(path) => walk(path, &mut paths),
+                Err(e) => paths.push(Err(BufrKitError::Other(e.to_string()))),
+            }
+        }
+        if paths.len() == n_paths {
+            paths.push(Err(BufrKitError::Other(format!("{}: no files match", name))));
+        }
+    }
+    paths
+}
+
+fn walk(path: PathBuf, paths: &mut Vec<Result<PathBuf, BufrKitError>>) {
+    if !path.is_dir() {
+        paths.push(Ok(path));
+        return;
+    }
+    let entries = fs::read_dir(&path)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<_>>>());
+    match entries {
+        Ok(mut entries) => {
+            entries.sort();
+            for entry in entries {
+                // Links to directories are not followed, as they may lead back up the tree
+                let skipped = if entry.is_dir() {
+                    entry.is_symlink()
+                } else {
+                    entry.extension().is_some_and(|extension| extension == "idx")
+                };
+                if !skipped {
+                    walk(entry, paths);
+                }
+            }
+        }
+        Err(e) => paths.push(Err(BufrKitError::Other(format!("{}: {}", path.display(), e)))),
+    }
+}
+
+/// Compression formats recognised by their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(bytes: &[u8]) -> Option<Compression> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// The reader, decompressing as it is read if it starts with the magic bytes of a
+/// compression format. Concatenated compressed streams are read one after another.
+pub fn decompressed<'a, R: BufRead + 'a>(mut r: R) -> Result<Box<dyn BufRead + 'a>, BufrKitError> {
+    Ok(match Compression::detect(r.fill_buf()?) {
+        Some(Compression::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(r))),
+        Some(Compression::Bzip2) => Box::new(BufReader::new(MultiBzDecoder::new(r))),
+        Some(Compression::Zstd) => Box::new(BufReader::new(ZstdDecoder::new(r)?)),
+        None => Box::new(r),
+    })
+}
+
+/// Decodes one zstd frame after another until the input ends
+struct ZstdDecoder<R: BufRead> {
+    /// None once a frame header failed to decode
+    frames: Option<StreamingDecoder<R, FrameDecoder>>,
+}
+
+impl<R: BufRead> ZstdDecoder<R> {
+    fn new(r: R) -> io::Result<Self> {
+        Ok(ZstdDecoder { frames: Some(StreamingDecoder::new(r).map_err(io::Error::other)?) })
+    }
+}
+
+impl<R: BufRead> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let frames = match self.frames.as_mut() {
+                Some(frames) => frames,
+                None => return Ok(0),
+            };
+            let n = frames.read(buf)?;
+            if n > 0 || buf.is_empty() || frames.get_mut().fill_buf()?.is_empty() {
+                return Ok(n);
+            }
+            let (r, decoder) = self.frames.take().expect("frames are being read").into_parts();
+            self.frames = Some(StreamingDecoder::new_with_decoder(r, decoder).map_err(io::Error::other)?);
+        }
+    }
+}
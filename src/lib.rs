@@ -7,60 +7,31 @@ extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod error;
 pub mod table;
 pub mod bufr;
 pub mod decoder;
 pub mod payload;
+pub mod input;
+pub mod index;
 mod cmd;
 
 #[cfg(test)]
 mod tests;
 
-use std::io::{Error};
 use std::fs::File;
+use std::thread;
 
-use clap::{App, Arg, SubCommand, ArgMatches};
-use crate::cmd::{Command, DecodeCommand, LookupCommand};
-use bitreader::BitReaderError;
-use std::num::ParseIntError;
+use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
+use crate::cmd::{parse_ids, Command, DecodeCommand, IndexBuildCommand, IndexQueryCommand, LookupCommand, LsCommand, LookupQuery, TablesCheckCommand, TablesDiffCommand};
+use crate::table::table::TableGroupId;
+use crate::decoder::DecodeOptions;
+use crate::bufr::Timestamp;
+use crate::index::IndexQuery;
+use crate::table::descriptor::Fxy;
 
+pub use crate::error::{BufrKitError, Position};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct BufrKitError {
-    message: String,
-}
-
-impl From<Error> for BufrKitError {
-    fn from(e: Error) -> Self {
-        BufrKitError {
-            message: e.to_string()
-        }
-    }
-}
-
-impl From<BitReaderError> for BufrKitError {
-    fn from(e: BitReaderError) -> Self {
-        BufrKitError {
-            message: format!("{}", e)
-        }
-    }
-}
-
-impl From<ParseIntError> for BufrKitError {
-    fn from(e: ParseIntError) -> Self {
-        BufrKitError {
-            message: format!("{}", e)
-        }
-    }
-}
-
-impl From<serde_json::Error> for BufrKitError {
-    fn from(e: serde_json::Error) -> Self {
-        BufrKitError {
-            message: e.to_string()
-        }
-    }
-}
 
 pub fn new_app<'a, 'b>() -> App<'a, 'b> {
     App::new("RustBufrKit")
@@ -75,11 +46,96 @@ pub fn new_app<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true))
         .subcommand(SubCommand::with_name("decode")
             .about("Decode BUFR messages")
+            .arg(Arg::with_name("lenient")
+                .long("lenient")
+                .help("Report malformed messages and continue with the next one, failing at the end"))
+            .arg(Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .help("Number of messages to decode in parallel, defaults to the number of CPUs")
+                .takes_value(true))
+            .arg(Arg::with_name("subset")
+                .long("subset")
+                .value_name("N")
+                .help("Decode only the values of subset N, counting from 0")
+                .takes_value(true))
+            .arg(Arg::with_name("only")
+                .long("only")
+                .value_name("IDS")
+                .help("Decode only the values of the given comma separated element descriptors")
+                .takes_value(true))
             .arg(Arg::with_name("INPUT")
-                .help("Input file")
+                .help("Input files, directories or glob patterns")
                 .default_value("-")
                 .required(false)
+                .multiple(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("ls")
+            .about("List the headers of BUFR messages without decoding their data")
+            .arg(Arg::with_name("lenient")
+                .long("lenient")
+                .help("Report malformed messages and continue with the next one, failing at the end"))
+            .arg(Arg::with_name("INPUT")
+                .help("Input files, directories or glob patterns")
+                .default_value("-")
+                .required(false)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("index")
+            .about("Index the messages of an archive to find them without scanning it again")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("build")
+                .about("Scan an archive and save the headers of its messages")
+                .arg(Arg::with_name("lenient")
+                    .long("lenient")
+                    .help("Report malformed messages and continue with the next one"))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Index file, defaults to the input file with an .idx extension appended")
+                    .takes_value(true))
+                .arg(Arg::with_name("INPUT")
+                    .help("Input file")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("query")
+                .about("Find messages of an archive in its index")
+                .arg(Arg::with_name("index")
+                    .long("index")
+                    .value_name("FILE")
+                    .help("Index file, defaults to the input file with an .idx extension appended")
+                    .takes_value(true))
+                .arg(Arg::with_name("centre")
+                    .long("centre")
+                    .value_name("N")
+                    .help("Originating centre")
+                    .takes_value(true))
+                .arg(Arg::with_name("category")
+                    .long("category")
+                    .value_name("N")
+                    .help("Data category")
+                    .takes_value(true))
+                .arg(Arg::with_name("from")
+                    .long("from")
+                    .value_name("TIME")
+                    .help("Earliest message time, inclusive, e.g. 2016-02-18 or 2016-02-18T23:00:00")
+                    .takes_value(true))
+                .arg(Arg::with_name("to")
+                    .long("to")
+                    .value_name("TIME")
+                    .help("Latest message time, exclusive")
+                    .takes_value(true))
+                .arg(Arg::with_name("descriptor")
+                    .long("descriptor")
+                    .value_name("ID")
+                    .help("Descriptor used by the message template, e.g. 012101")
+                    .takes_value(true))
+                .arg(Arg::with_name("INPUT")
+                    .help("Input file")
+                    .required(true)
+                    .index(1))))
         .subcommand(SubCommand::with_name("encode")
             .about("Encode BUFR messages")
             .arg(Arg::with_name("INPUT")
@@ -89,32 +145,191 @@ pub fn new_app<'a, 'b>() -> App<'a, 'b> {
                 .index(1)))
         .subcommand(SubCommand::with_name("lookup")
             .about("Lookup BUFR descriptors")
+            .arg(Arg::with_name("base-dir")
+                .long("base-dir")
+                .value_name("DIR")
+                .help("Base directory of table groups")
+                .default_value("_definitions/tables")
+                .takes_value(true))
+            .arg(Arg::with_name("master-table-number")
+                .long("master-table-number")
+                .value_name("NUMBER")
+                .help("Master table number, 0 for meteorology")
+                .default_value("0")
+                .takes_value(true))
+            .arg(Arg::with_name("centre")
+                .long("centre")
+                .value_name("NUMBER")
+                .help("Originating centre of local tables")
+                .default_value("0")
+                .takes_value(true))
+            .arg(Arg::with_name("sub-centre")
+                .long("sub-centre")
+                .value_name("NUMBER")
+                .help("Originating sub-centre of local tables")
+                .default_value("0")
+                .takes_value(true))
+            .arg(Arg::with_name("table-version")
+                .long("table-version")
+                .value_name("NUMBER")
+                .help("Master table version number")
+                .default_value("25")
+                .takes_value(true))
+            .arg(Arg::with_name("code")
+                .long("code")
+                .help("Show code and flag tables of element descriptors")
+                .conflicts_with("meta"))
+            .arg(Arg::with_name("meta")
+                .long("meta")
+                .help("Show the class descriptions of descriptors"))
+            .arg(Arg::with_name("search")
+                .long("search")
+                .value_name("KEYWORDS")
+                .help("Search descriptors by name")
+                .takes_value(true)
+                .conflicts_with_all(&["IDS", "used-by", "code", "meta"]))
+            .arg(Arg::with_name("regex")
+                .long("regex")
+                .help("Treat the search keywords as a regular expression")
+                .requires("search"))
+            .arg(Arg::with_name("used-by")
+                .long("used-by")
+                .value_name("ID")
+                .help("List sequences that contain the descriptor")
+                .takes_value(true)
+                .conflicts_with_all(&["IDS", "code", "meta"]))
             .arg(Arg::with_name("IDS")
                 .help("Comma separated list of descriptor IDs")
-                .required(true)
+                .required_unless_one(&["search", "used-by"])
                 .index(1)))
+        .subcommand(SubCommand::with_name("tables")
+            .about("Work with BUFR tables")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("check")
+                .about("Check a table group for consistency")
+                .arg(Arg::with_name("DIR")
+                    .help("Table group directory, e.g. _definitions/tables/0/0_0/25")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("diff")
+                .about("Compare two table groups")
+                .arg(Arg::with_name("base-dir")
+                    .long("base-dir")
+                    .value_name("DIR")
+                    .help("Base directory of table groups")
+                    .default_value("_definitions/tables")
+                    .takes_value(true))
+                .arg(Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Output format")
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .takes_value(true))
+                .arg(Arg::with_name("OLD")
+                    .help("Old table group relative to the base directory, e.g. 0/0_0/32")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("NEW")
+                    .help("New table group relative to the base directory, e.g. 0/0_0/33")
+                    .required(true)
+                    .index(2))))
 }
 
 pub fn run_app() -> Result<(), BufrKitError> {
     let matches = new_app().get_matches();
     match matches.subcommand() {
         ("decode", Some(sub_m)) => run_decoder(sub_m),
+        ("ls", Some(sub_m)) => run_ls(sub_m),
+        ("index", Some(sub_m)) => run_index(sub_m),
         ("encode", Some(sub_m)) => unimplemented!("encode"),
         ("lookup", Some(sub_m)) => run_lookup(sub_m),
-        (s, _) => Err(BufrKitError {
-            message: format!("Unknown command: [{}]", s)
-        })
+        ("tables", Some(sub_m)) => run_tables(sub_m),
+        (s, _) => Err(BufrKitError::Other(format!("Unknown command: [{}]", s)))
     }
 }
 
 fn run_decoder(matches: &ArgMatches) -> Result<(), BufrKitError> {
-    let input_file = matches.value_of("INPUT").unwrap();
-    let mut cmd = DecodeCommand::new(input_file);
+    let input_files = matches.values_of("INPUT").unwrap().collect();
+    let options = DecodeOptions {
+        lenient: matches.is_present("lenient"),
+        subset: matches.value_of("subset").map(|n| n.parse::<usize>()).transpose()?,
+        projection: matches.value_of("only").map(parse_ids).transpose()?.map(|ids| ids.into_iter().collect()),
+        ..DecodeOptions::default()
+    };
+    let n_threads = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse::<usize>()?,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let mut cmd = DecodeCommand::new(input_files, options, n_threads);
     cmd.run()
 }
 
+fn run_ls(matches: &ArgMatches) -> Result<(), BufrKitError> {
+    let input_files = matches.values_of("INPUT").unwrap().collect();
+    let options = DecodeOptions { lenient: matches.is_present("lenient"), ..DecodeOptions::default() };
+    LsCommand::new(input_files, options).run()
+}
+
+fn run_index(matches: &ArgMatches) -> Result<(), BufrKitError> {
+    match matches.subcommand() {
+        ("build", Some(sub_m)) => {
+            let options = DecodeOptions { lenient: sub_m.is_present("lenient"), ..DecodeOptions::default() };
+            IndexBuildCommand::new(sub_m.value_of("INPUT").unwrap(), sub_m.value_of("output"), options).run()
+        }
+        ("query", Some(sub_m)) => {
+            let query = IndexQuery {
+                centre: sub_m.value_of("centre").map(|n| n.parse::<u16>()).transpose()?,
+                data_category: sub_m.value_of("category").map(|n| n.parse::<u8>()).transpose()?,
+                from: sub_m.value_of("from").map(|t| t.parse::<Timestamp>()).transpose()?,
+                to: sub_m.value_of("to").map(|t| t.parse::<Timestamp>()).transpose()?,
+                descriptor: sub_m.value_of("descriptor").map(|id| id.parse::<Fxy>()).transpose()?,
+            };
+            IndexQueryCommand::new(sub_m.value_of("INPUT").unwrap(), sub_m.value_of("index"), query).run()
+        }
+        (s, _) => Err(BufrKitError::Other(format!("Unknown index command: [{}]", s)))
+    }
+}
+
 fn run_lookup(matches: &ArgMatches) -> Result<(), BufrKitError> {
-    let ids = matches.value_of("IDS").unwrap();
-    let mut cmd = LookupCommand::new(ids);
+    let query = if let Some(pattern) = matches.value_of("search") {
+        LookupQuery::Search(pattern, matches.is_present("regex"))
+    } else if let Some(id) = matches.value_of("used-by") {
+        LookupQuery::UsedBy(id)
+    } else if matches.is_present("code") {
+        LookupQuery::Code(matches.value_of("IDS").unwrap())
+    } else if matches.is_present("meta") {
+        LookupQuery::Meta(matches.value_of("IDS").unwrap())
+    } else {
+        LookupQuery::Ids(matches.value_of("IDS").unwrap())
+    };
+    let table_group_id = TableGroupId {
+        base_dir: matches.value_of("base-dir").unwrap().to_owned(),
+        master_table_number: matches.value_of("master-table-number").unwrap().parse::<isize>()?,
+        centre_number: matches.value_of("centre").unwrap().parse::<isize>()?,
+        sub_centre_number: matches.value_of("sub-centre").unwrap().parse::<isize>()?,
+        version_number: matches.value_of("table-version").unwrap().parse::<isize>()?,
+    };
+    let mut cmd = LookupCommand::new(table_group_id, query);
     cmd.run()
 }
+
+fn run_tables(matches: &ArgMatches) -> Result<(), BufrKitError> {
+    match matches.subcommand() {
+        ("check", Some(sub_m)) => {
+            let dir = sub_m.value_of("DIR").unwrap();
+            let mut cmd = TablesCheckCommand::new(dir);
+            cmd.run()
+        }
+        ("diff", Some(sub_m)) => {
+            let mut cmd = TablesDiffCommand::new(
+                sub_m.value_of("base-dir").unwrap(),
+                sub_m.value_of("OLD").unwrap(),
+                sub_m.value_of("NEW").unwrap(),
+                sub_m.value_of("format").unwrap() == "json",
+            );
+            cmd.run()
+        }
+        (s, _) => Err(BufrKitError::Other(format!("Unknown tables command: [{}]", s)))
+    }
+}
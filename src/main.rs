@@ -1,5 +1,9 @@
+use std::process;
 use rustbufrkit::run_app;
 
 fn main() {
-    run_app().unwrap();
+    if let Err(e) = run_app() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -1,42 +1,657 @@
-use crate::table::template::{Visitor, Node, Template};
-use std::rc::Rc;
-use std::cell::Ref;
+pub mod program;
+
+use crate::table::template::{Children, Flow, Template, Visitor, VisitResult};
+use crate::payload::program::{Program, ValueStream};
 use bitreader::BitReader;
-use crate::table::descriptor::{ElementDescriptor, ReplicationDescriptor, OperatorDescriptor, SequenceDescriptor};
-use crate::bufr::SimpleData;
+use crate::table::descriptor::{Descriptor, ElementDescriptor, Fxy, OperatorDescriptor, ReplicationDescriptor};
+use crate::table::table::TableGroup;
+use crate::bufr::{DataValue, SimpleData, Warning, WarningKind};
+use crate::decoder::{check_limit, DecodeLimits};
+use crate::{BufrKitError, Position};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+/// How the bits of an element are turned into a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Numeric { scale: isize, refval: i64 },
+    CodeTable,
+    FlagTable,
+    /// Characters, one per byte
+    Text,
+}
+
+/// Whether a delayed replication factor is one of delayed repetition, i.e. 031011 or
+/// 031012, whose members are present once in the data and repeated when decoded
+pub fn is_repetition_factor(id: Fxy) -> bool {
+    id.x() == 31 && (id.y() == 11 || id.y() == 12)
+}
+
+/// An element as it is laid out in the data, after applying any operators in effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueSpec {
+    pub id: Fxy,
+    pub kind: ValueKind,
+    pub nbits: usize,
+}
 
-struct BinaryPayloadDecodingVisitor<'a> {
-    template: &'a Template,
-    br: &'a BitReader<'a>,
-    data: Vec<SimpleData>
+/// Changes made by operator descriptors to how the following elements are read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperatorState {
+    nbits_increment: isize,
+    scale_increment: isize,
+    /// Y of 207YYY, which increases scale, reference value and width together
+    precision_increment: u32,
+    text_nbits: Option<usize>,
 }
 
-impl<'a> Visitor for BinaryPayloadDecodingVisitor<'a> {
-    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor) {
-        unimplemented!()
+impl OperatorState {
+    /// Whether `apply` knows how to apply the operator
+    pub fn is_supported(id: Fxy) -> bool {
+        matches!(id.x(), 1 | 2 | 5 | 7 | 8)
+    }
+
+    /// Apply an operator descriptor. Returns the number of characters to read when
+    /// the operator inserts a character value, i.e. 205YYY.
+    pub fn apply(&mut self, id: Fxy) -> Result<Option<usize>, BufrKitError> {
+        let y = id.y() as isize;
+        match id.x() {
+            1 => self.nbits_increment = if y == 0 { 0 } else { y - 128 },
+            2 => self.scale_increment = if y == 0 { 0 } else { y - 128 },
+            5 => return Ok(Some(y as usize)),
+            7 => self.precision_increment = y as u32,
+            8 => self.text_nbits = if y == 0 { None } else { Some(y as usize * 8) },
+            _ => return Err(BufrKitError::Unsupported(format!("{}: operator is not supported", id))),
+        }
+        Ok(None)
+    }
+
+    /// Where and how to read the element given the operators in effect. Operators do
+    /// not apply to code and flag tables, characters or class 31 elements.
+    pub fn spec(&self, descriptor: &ElementDescriptor) -> Result<ValueSpec, BufrKitError> {
+        let nbits = descriptor.nbits as usize;
+        let (kind, nbits) = if descriptor.unit == "CCITT IA5" {
+            (ValueKind::Text, self.text_nbits.unwrap_or(nbits))
+        } else if descriptor.unit.contains("CODE TABLE") {
+            (ValueKind::CodeTable, nbits)
+        } else if descriptor.unit.contains("FLAG TABLE") {
+            (ValueKind::FlagTable, nbits)
+        } else if descriptor.id.x() == 31 {
+            (ValueKind::Numeric { scale: descriptor.scale, refval: descriptor.refval as i64 }, nbits)
+        } else {
+            let p = self.precision_increment;
+            let refval = 10i64.checked_pow(p)
+                .and_then(|x| x.checked_mul(descriptor.refval as i64))
+                .ok_or_else(|| BufrKitError::InvalidDescriptor(
+                    format!("{}: reference value overflows with 207{:03}", descriptor.id, p)))?;
+            let kind = ValueKind::Numeric {
+                scale: descriptor.scale + self.scale_increment + p as isize,
+                refval,
+            };
+            (kind, (nbits as isize + self.nbits_increment + (10 * p as isize + 2) / 3).max(0) as usize)
+        };
+        if nbits == 0 || (kind != ValueKind::Text && nbits > 64) {
+            return Err(BufrKitError::InvalidDescriptor(
+                format!("{}: cannot read a value of {} bits", descriptor.id, nbits)));
+        }
+        Ok(ValueSpec { id: descriptor.id, kind, nbits })
+    }
+}
+
+/// Reads template data into subsets, one subset at a time for uncompressed data
+/// and all subsets at once for compressed data
+pub struct PayloadReader<'a, 'b> {
+    table_group: &'a TableGroup,
+    br: &'a mut BitReader<'b>,
+    limits: DecodeLimits,
+    position: Position,
+    compressed: bool,
+    n_subsets: usize,
+    /// The only subset whose values are kept, if any
+    selected: Option<usize>,
+    /// The only descriptors whose values are kept, if any
+    projection: Option<&'a HashSet<Fxy>>,
+    subsets: Vec<Vec<DataValue>>,
+    /// Number of values of each subset when each delayed repetition being read started
+    repetitions: Vec<Vec<usize>>,
+    current: usize,
+    n_values: usize,
+    warnings: Vec<Warning>,
+}
+
+impl<'a, 'b> PayloadReader<'a, 'b> {
+    /// `position` locates section 4 of the message. Its bit offset is taken from
+    /// the bit reader as values are read.
+    pub fn new(table_group: &'a TableGroup,
+               br: &'a mut BitReader<'b>,
+               n_subsets: usize,
+               compressed: bool,
+               limits: DecodeLimits,
+               position: Position) -> Self {
+        PayloadReader {
+            table_group,
+            br,
+            limits,
+            position,
+            compressed,
+            n_subsets,
+            selected: None,
+            projection: None,
+            subsets: vec![Vec::new(); n_subsets],
+            repetitions: Vec::new(),
+            current: 0,
+            n_values: 0,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Keep the values of the given subset only. Earlier subsets of uncompressed data
+    /// are skipped over and later ones are not read at all.
+    pub fn select_subset(&mut self, index: usize) -> Result<(), BufrKitError> {
+        if index >= self.n_subsets {
+            return Err(BufrKitError::SubsetOutOfRange { index, n_subsets: self.n_subsets });
+        }
+        self.selected = Some(index);
+        self.subsets = vec![Vec::new()];
+        Ok(())
+    }
+
+    /// Keep the values of the given descriptors only
+    pub fn project(&mut self, ids: &'a HashSet<Fxy>) {
+        self.projection = Some(ids);
+    }
+
+    /// Number of subsets to go through, which stops at the selected subset if any
+    pub fn n_subsets(&self) -> usize {
+        self.selected.map_or(self.n_subsets, |index| index + 1)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Move on to the given subset of uncompressed data
+    pub fn start_subset(&mut self, index: usize) {
+        self.current = index;
+    }
+
+    pub fn read(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        if spec.kind == ValueKind::Text {
+            return self.read_text(spec);
+        }
+        if self.compressed {
+            let min = self.read_bits(spec.nbits)?;
+            let nbits_diff = self.br.read_u8(6)? as usize;
+            let read_one = |reader: &mut Self| -> Result<Option<u64>, BufrKitError> {
+                Ok(if nbits_diff == 0 {
+                    not_missing(spec, min)
+                } else {
+                    let diff = reader.read_bits(nbits_diff)?;
+                    if is_all_ones(diff, nbits_diff) {
+                        None
+                    } else {
+                        Some(min.checked_add(diff).ok_or_else(|| BufrKitError::InvalidSection {
+                            section: 4,
+                            message: format!("{}: minimum {} plus difference {} overflows", spec.id, min, diff),
+                        })?)
+                    }
+                })
+            };
+            if let Some(index) = self.selected {
+                self.br.skip((index * nbits_diff) as u64)?;
+                let raw = read_one(self)?;
+                self.br.skip(((self.n_subsets - index - 1) * nbits_diff) as u64)?;
+                self.push(index, spec, raw)?;
+            } else {
+                for i in 0..self.n_subsets {
+                    let raw = read_one(self)?;
+                    self.push(i, spec, raw)?;
+                }
+            }
+        } else if self.is_skipped(self.current) {
+            self.br.skip(spec.nbits as u64)?;
+        } else {
+            let raw = self.read_bits(spec.nbits)?;
+            self.push(self.current, spec, not_missing(spec, raw))?;
+        }
+        Ok(())
+    }
+
+    /// Pass over an element without producing its value
+    pub fn skip(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        self.br.skip(spec.nbits as u64)?;
+        if self.compressed {
+            let nbits_diff = self.br.read_u8(6)? as usize;
+            let nbits_diff = if spec.kind == ValueKind::Text { nbits_diff * 8 } else { nbits_diff };
+            self.br.skip((self.n_subsets * nbits_diff) as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Pass over the given number of bits of uncompressed data
+    pub fn skip_bits(&mut self, nbits: usize) -> Result<(), BufrKitError> {
+        debug_assert!(!self.compressed, "bits are not skipped in compressed data");
+        Ok(self.br.skip(nbits as u64)?)
+    }
+
+    /// Read a delayed replication factor, which must be the same for all subsets of
+    /// compressed data
+    pub fn read_replication_factor(&mut self, spec: &ValueSpec) -> Result<usize, BufrKitError> {
+        let raw = self.read_bits(spec.nbits)?;
+        if self.compressed {
+            if self.br.read_u8(6)? != 0 {
+                return Err(BufrKitError::InvalidSection {
+                    section: 4,
+                    message: format!("{}: replication factor differs between compressed subsets", spec.id),
+                });
+            }
+            for i in 0..self.n_subsets {
+                self.push(i, spec, Some(raw))?;
+            }
+        } else {
+            self.push(self.current, spec, Some(raw))?;
+        }
+        let count = match spec.kind {
+            ValueKind::Numeric { refval, .. } => numeric_value(spec, raw, refval)?.max(0) as usize,
+            _ => raw as usize,
+        };
+        check_limit("replication count", count, self.limits.max_replication_count)?;
+        Ok(count)
+    }
+
+    /// Start reading the members of a delayed repetition
+    pub fn start_repetition(&mut self) {
+        self.repetitions.push(self.subsets.iter().map(|subset| subset.len()).collect());
+    }
+
+    /// Repeat the values read since the matching `start_repetition`, so that they
+    /// appear `count` times in total
+    pub fn end_repetition(&mut self, count: usize) -> Result<(), BufrKitError> {
+        let starts = self.repetitions.pop().expect("repetition has started");
+        for (subset, start) in self.subsets.iter_mut().zip(starts) {
+            let values = subset[start..].to_vec();
+            for _ in 1..count {
+                self.n_values += values.len();
+                check_limit("number of values", self.n_values, self.limits.max_output_values)?;
+                subset.extend_from_slice(&values);
+            }
+        }
+        Ok(())
+    }
+
+    /// The decoded subsets and any warnings raised along the way. Only the selected
+    /// subset is returned if there is one.
+    pub fn finish(self) -> (Vec<Vec<DataValue>>, Vec<Warning>) {
+        (self.subsets, self.warnings)
+    }
+
+    fn is_skipped(&self, subset: usize) -> bool {
+        self.selected.is_some_and(|index| index != subset)
+    }
+
+    fn is_projected_out(&self, id: Fxy) -> bool {
+        self.projection.is_some_and(|ids| !ids.contains(&id))
     }
 
-    fn visit_replication_descriptor<'b>(&mut self, descriptor: &ReplicationDescriptor, children: Ref<'b, Vec<Rc<Node>>>) {
-        unimplemented!()
+    fn read_text(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        if !self.compressed && self.is_skipped(self.current) {
+            return Ok(self.br.skip(spec.nbits as u64)?);
+        }
+        let nbytes = spec.nbits / 8;
+        let min = self.read_bytes(nbytes)?;
+        self.br.skip((spec.nbits % 8) as u64)?;
+        if self.compressed {
+            let nbytes_diff = self.br.read_u8(6)? as usize;
+            for i in 0..self.n_subsets {
+                if self.is_skipped(i) {
+                    self.br.skip((nbytes_diff * 8) as u64)?;
+                    continue;
+                }
+                let value = if nbytes_diff == 0 { min.clone() } else { self.read_bytes(nbytes_diff)? };
+                self.push_value(i, spec.id, text_value(value))?;
+            }
+            Ok(())
+        } else {
+            self.push_value(self.current, spec.id, text_value(min))
+        }
     }
 
-    fn visit_operator_descriptor(&mut self, descriptor: &OperatorDescriptor) {
-        unimplemented!()
+    fn read_bits(&mut self, nbits: usize) -> Result<u64, BufrKitError> {
+        if nbits == 0 {
+            Ok(0)
+        } else {
+            Ok(self.br.read_u64(nbits as u8)?)
+        }
     }
 
-    fn visit_sequence_descriptor<'b>(&mut self, descriptor: &SequenceDescriptor, children: Ref<'b, Vec<Rc<Node>>>) {
-        unimplemented!()
+    fn read_bytes(&mut self, nbytes: usize) -> Result<Vec<u8>, BufrKitError> {
+        let mut b = vec![0u8; nbytes];
+        self.br.read_u8_slice(&mut b)?;
+        Ok(b)
     }
 
-    fn visit_replication_factor(&mut self, descriptor: &ElementDescriptor) {
-        unimplemented!()
+    fn push(&mut self, subset: usize, spec: &ValueSpec, raw: Option<u64>) -> Result<(), BufrKitError> {
+        if self.is_skipped(subset) || self.is_projected_out(spec.id) {
+            return Ok(());
+        }
+        let value = match (raw, spec.kind) {
+            (None, _) => SimpleData::MISSING,
+            (Some(raw), ValueKind::Numeric { scale, refval }) => {
+                let v = numeric_value(spec, raw, refval)?;
+                if scale == 0 { SimpleData::I64(v) } else { SimpleData::F64(v as f64 / 10f64.powi(scale as i32)) }
+            }
+            (Some(raw), ValueKind::CodeTable) => {
+                self.check_code(spec.id, raw as u32);
+                SimpleData::U32(raw as u32)
+            }
+            (Some(raw), ValueKind::FlagTable) => SimpleData::FLAG(raw as u32, spec.nbits as u8),
+            (Some(_), ValueKind::Text) => unreachable!("text is read by read_text"),
+        };
+        self.push_value(subset, spec.id, value)
     }
 
-    fn exit_replication_descriptor(&mut self) {
-        unimplemented!()
+    fn push_value(&mut self, subset: usize, id: Fxy, value: SimpleData) -> Result<(), BufrKitError> {
+        if self.is_skipped(subset) || self.is_projected_out(id) {
+            return Ok(());
+        }
+        self.n_values += 1;
+        check_limit("number of values", self.n_values, self.limits.max_output_values)?;
+        let slot = if self.selected.is_some() { 0 } else { subset };
+        self.subsets[slot].push(DataValue { id, value });
+        Ok(())
     }
 
-    fn exit_sequence_descriptor(&mut self) {
-        unimplemented!()
+    fn check_code(&mut self, id: Fxy, value: u32) {
+        let (kind, message) = match self.table_group.code_and_flag().get(id) {
+            None => (WarningKind::MissingCodeTable, "no code table in the table group".to_owned()),
+            Some(entries) if !entries.contains_key(&(value as isize)) =>
+                (WarningKind::UnknownCodeValue, format!("{} is not in the code table", value)),
+            Some(_) => return,
+        };
+        let position = Position { bit_offset: self.br.position(), ..self.position };
+        self.warnings.push(Warning { kind, descriptor: Some(id), position, message });
     }
-}
\ No newline at end of file
+}
+
+fn is_all_ones(raw: u64, nbits: usize) -> bool {
+    nbits >= 64 && raw == u64::MAX || nbits < 64 && raw == (1u64 << nbits) - 1
+}
+
+/// All bits set means missing, except for single bit values and class 31 elements
+fn not_missing(spec: &ValueSpec, raw: u64) -> Option<u64> {
+    if spec.nbits > 1 && spec.id.x() != 31 && is_all_ones(raw, spec.nbits) {
+        None
+    } else {
+        Some(raw)
+    }
+}
+
+fn text_value(bytes: Vec<u8>) -> SimpleData {
+    if !bytes.is_empty() && bytes.iter().all(|&b| b == 0xff) {
+        SimpleData::MISSING
+    } else {
+        SimpleData::BYTES(bytes.iter().map(|&x| x as char).collect())
+    }
+}
+
+/// The first operator of the template that `OperatorState` does not support, if any
+pub fn unsupported_operator(template: &Template) -> Option<Fxy> {
+    find_unsupported_operator(template.root().children())
+}
+
+fn find_unsupported_operator(children: Children<'_>) -> Option<Fxy> {
+    children.iter().find_map(|node| match node.descriptor() {
+        Descriptor::Operator(descriptor) if !OperatorState::is_supported(descriptor.id) => Some(descriptor.id),
+        _ => find_unsupported_operator(node.children()),
+    })
+}
+
+/// Decode template data by running a compiled template
+pub fn decode_with_program(program: &Program,
+                           mut reader: PayloadReader) -> Result<(Vec<Vec<DataValue>>, Vec<Warning>), BufrKitError> {
+    program.run(&mut reader)?;
+    Ok(reader.finish())
+}
+
+/// Encode subsets as template data by running a compiled template. The data is always
+/// written uncompressed, so section 3 of the message must not flag it as compressed.
+pub fn encode_with_program(program: &Program, subsets: &[Vec<DataValue>]) -> Result<Vec<u8>, BufrKitError> {
+    let mut writer = PayloadWriter::new(subsets);
+    program.run(&mut writer)?;
+    writer.finish()
+}
+
+/// Writes the values of subsets in the order a program asks for them, one subset after
+/// another. Compressed data is not supported.
+pub struct PayloadWriter<'a> {
+    subsets: &'a [Vec<DataValue>],
+    current: usize,
+    next: usize,
+    /// Index of the next value when each delayed repetition being written started
+    repetitions: Vec<usize>,
+    n_written: usize,
+    bits: BitWriter,
+}
+
+impl<'a> PayloadWriter<'a> {
+    pub fn new(subsets: &'a [Vec<DataValue>]) -> Self {
+        PayloadWriter { subsets, current: 0, next: 0, repetitions: Vec::new(), n_written: 0, bits: BitWriter::default() }
+    }
+
+    /// The written bits, padded with zeros to a whole number of bytes. Fails if some
+    /// values were not asked for.
+    pub fn finish(self) -> Result<Vec<u8>, BufrKitError> {
+        let n_values: usize = self.subsets.iter().map(|subset| subset.len()).sum();
+        if self.n_written != n_values {
+            return Err(encoding_error(format!("{} of {} values are not in the template", n_values - self.n_written, n_values)));
+        }
+        Ok(self.bits.into_bytes())
+    }
+
+    fn next_value(&mut self, id: Fxy) -> Result<&'a SimpleData, BufrKitError> {
+        let subsets = self.subsets;
+        let value = subsets[self.current].get(self.next)
+            .ok_or_else(|| encoding_error(format!("{}: subset {} has no more values", id, self.current)))?;
+        if value.id != id {
+            return Err(encoding_error(format!("{}: subset {} has a value of {} instead", id, self.current, value.id)));
+        }
+        self.next += 1;
+        self.n_written += 1;
+        Ok(&value.value)
+    }
+}
+
+impl<'a> ValueStream for PayloadWriter<'a> {
+    fn n_subsets(&self) -> usize {
+        self.subsets.len()
+    }
+
+    fn is_compressed(&self) -> bool {
+        false
+    }
+
+    fn start_subset(&mut self, index: usize) {
+        self.current = index;
+        self.next = 0;
+    }
+
+    fn value(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        let value = self.next_value(spec.id)?;
+        if spec.kind == ValueKind::Text {
+            let nbytes = spec.nbits / 8;
+            let bytes: Vec<u8> = match value {
+                SimpleData::MISSING => vec![0xff; nbytes],
+                SimpleData::BYTES(s) if s.chars().count() <= nbytes =>
+                    s.chars().map(|c| c as u8).chain(std::iter::repeat(b' ')).take(nbytes).collect(),
+                _ => return Err(encoding_error(format!("{}: cannot encode {:?} in {} characters", spec.id, value, nbytes))),
+            };
+            for b in bytes {
+                self.bits.write(b as u64, 8);
+            }
+            self.bits.write(0, spec.nbits % 8);
+        } else {
+            let raw = raw_value(spec, value)?;
+            self.bits.write(raw, spec.nbits);
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        Err(BufrKitError::Unsupported(format!("{}: values cannot be skipped when encoding", spec.id)))
+    }
+
+    fn skip_bits(&mut self, _nbits: usize) -> Result<(), BufrKitError> {
+        Err(BufrKitError::Unsupported("values cannot be skipped when encoding".to_owned()))
+    }
+
+    fn start_repetition(&mut self) {
+        self.repetitions.push(self.next);
+    }
+
+    /// Pass over the repeated values, which are only written once
+    fn end_repetition(&mut self, count: usize) -> Result<(), BufrKitError> {
+        let start = self.repetitions.pop().expect("repetition has started");
+        let subset = &self.subsets[self.current];
+        let n = self.next - start;
+        for _ in 1..count {
+            if subset.get(self.next..self.next + n) != Some(&subset[start..start + n]) {
+                return Err(encoding_error(format!("subset {}: values of a delayed repetition are not repeated", self.current)));
+            }
+            self.next += n;
+            self.n_written += n;
+        }
+        Ok(())
+    }
+
+    fn replication_factor(&mut self, spec: &ValueSpec) -> Result<usize, BufrKitError> {
+        let value = self.next_value(spec.id)?;
+        let raw = raw_value(spec, value)?;
+        self.bits.write(raw, spec.nbits);
+        Ok(match spec.kind {
+            ValueKind::Numeric { refval, .. } => numeric_value(spec, raw, refval)?.max(0) as usize,
+            _ => raw as usize,
+        })
+    }
+}
+
+/// The raw bits plus the reference value, which can overflow for elements widened
+/// to 64 bits by operators
+fn numeric_value(spec: &ValueSpec, raw: u64, refval: i64) -> Result<i64, BufrKitError> {
+    i64::try_from(raw).ok()
+        .and_then(|raw| raw.checked_add(refval))
+        .ok_or_else(|| BufrKitError::InvalidSection {
+            section: 4,
+            message: format!("{}: {} plus reference value {} overflows", spec.id, raw, refval),
+        })
+}
+
+/// The bits of a value, the reverse of `PayloadReader::push`
+fn raw_value(spec: &ValueSpec, value: &SimpleData) -> Result<u64, BufrKitError> {
+    let raw = match (value, spec.kind) {
+        (SimpleData::MISSING, _) => return Ok(if spec.nbits >= 64 { u64::MAX } else { (1u64 << spec.nbits) - 1 }),
+        (SimpleData::I64(v), ValueKind::Numeric { refval, .. }) => v.checked_sub(refval),
+        (SimpleData::F64(v), ValueKind::Numeric { scale, refval }) =>
+            ((v * 10f64.powi(scale as i32)).round() as i64).checked_sub(refval),
+        (SimpleData::U32(v), ValueKind::CodeTable) | (SimpleData::FLAG(v, _), ValueKind::FlagTable) => Some(*v as i64),
+        _ => return Err(encoding_error(format!("{}: cannot encode {:?} as {:?}", spec.id, value, spec.kind))),
+    };
+    match raw {
+        Some(raw) if raw >= 0 && (spec.nbits >= 64 || (raw as u64) >> spec.nbits == 0) => Ok(raw as u64),
+        _ => Err(encoding_error(format!("{}: {:?} does not fit in {} bits", spec.id, value, spec.nbits))),
+    }
+}
+
+fn encoding_error(message: String) -> BufrKitError {
+    BufrKitError::InvalidSection { section: 4, message }
+}
+
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    nbits: usize,
+}
+
+impl BitWriter {
+    /// Write the lowest `nbits` bits of the value, most significant first
+    pub(crate) fn write(&mut self, value: u64, nbits: usize) {
+        for i in (0..nbits).rev() {
+            if self.nbits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if i < 64 && (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.nbits % 8);
+            }
+            self.nbits += 1;
+        }
+    }
+
+    /// The written bits, padded with zeros to a whole number of bytes
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Decode template data by walking the template once per subset, or once for all
+/// subsets of compressed data
+pub fn decode_with_visitor(template: &Template,
+                           reader: PayloadReader) -> Result<(Vec<Vec<DataValue>>, Vec<Warning>), BufrKitError> {
+    let mut visitor = BinaryPayloadDecodingVisitor { reader, operators: OperatorState::default() };
+    if visitor.reader.is_compressed() {
+        template.accept(&mut visitor)?;
+    } else {
+        for i in 0..visitor.reader.n_subsets() {
+            visitor.reader.start_subset(i);
+            visitor.operators = OperatorState::default();
+            template.accept(&mut visitor)?;
+        }
+    }
+    Ok(visitor.reader.finish())
+}
+
+struct BinaryPayloadDecodingVisitor<'a, 'b> {
+    reader: PayloadReader<'a, 'b>,
+    operators: OperatorState,
+}
+
+impl<'a, 'b> Visitor for BinaryPayloadDecodingVisitor<'a, 'b> {
+    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor) -> VisitResult {
+        let spec = self.operators.spec(descriptor)?;
+        self.reader.read(&spec)?;
+        Ok(Flow::Continue)
+    }
+
+    fn visit_replication_descriptor(&mut self, descriptor: &ReplicationDescriptor,
+                                    children: Children<'_>) -> VisitResult {
+        let (count, members, repeated) = if descriptor.id.y() == 0 {
+            let factor = match children.get(0).map(|node| node.descriptor()) {
+                Some(Descriptor::Element(factor)) => factor,
+                _ => return Err(BufrKitError::InvalidDescriptor(
+                    format!("{}: expected an element descriptor as replication factor", descriptor.id))),
+            };
+            let spec = self.operators.spec(factor)?;
+            (self.reader.read_replication_factor(&spec)?, children.skip(1), is_repetition_factor(factor.id))
+        } else {
+            (descriptor.id.y() as usize, children, false)
+        };
+        if repeated && count > 0 {
+            self.reader.start_repetition();
+            let flow = members.accept(self)?;
+            self.reader.end_repetition(count)?;
+            return Ok(if flow == Flow::Stop { Flow::Stop } else { Flow::SkipChildren });
+        }
+        for _ in 0..count {
+            if members.accept(self)? == Flow::Stop {
+                return Ok(Flow::Stop);
+            }
+        }
+        Ok(Flow::SkipChildren)
+    }
+
+    fn visit_operator_descriptor(&mut self, descriptor: &OperatorDescriptor) -> VisitResult {
+        if let Some(n_chars) = self.operators.apply(descriptor.id)? {
+            let spec = ValueSpec { id: descriptor.id, kind: ValueKind::Text, nbits: n_chars * 8 };
+            self.reader.read(&spec)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,306 @@
+use crate::table::template::{Children, NodeRef, Template};
+use crate::table::descriptor::{Descriptor, ElementDescriptor, Fxy};
+use crate::payload::{is_repetition_factor, OperatorState, PayloadReader, ValueKind, ValueSpec};
+use crate::BufrKitError;
+use std::collections::HashSet;
+
+/// How an instruction finds the width, scale and reference value of an element
+#[derive(Debug, Clone)]
+pub enum Operand {
+    /// Known when compiling
+    Resolved(ValueSpec),
+    /// Worked out from the operators in effect when the program runs, for elements
+    /// whose operators depend on how many times a replication has repeated
+    Deferred(ElementDescriptor),
+}
+
+/// A step of a compiled template
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    /// Read or write an element
+    Value(Operand),
+    /// Pass over an element that is not wanted
+    Skip(Operand),
+    /// Pass over a run of unwanted elements in uncompressed data, which take this many bits
+    SkipBits(usize),
+    /// Repeat the instructions up to the matching `EndReplication`, at index `end`
+    BeginReplication { count: usize, end: usize },
+    /// Read or write the replication factor, then repeat the instructions up to the
+    /// matching `EndReplication`, at index `end`. The instructions run once for delayed
+    /// repetition, whose values are repeated instead.
+    BeginDelayedReplication { factor: Operand, end: usize },
+    EndReplication,
+    /// Apply an operator descriptor, reading or writing characters for 205YYY
+    Operator(Fxy),
+}
+
+/// Where the values of a program come from or go to
+pub trait ValueStream {
+    fn n_subsets(&self) -> usize;
+
+    /// Whether all subsets are handled at once by a single run of the program
+    fn is_compressed(&self) -> bool;
+
+    fn start_subset(&mut self, index: usize);
+
+    fn value(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError>;
+
+    /// Pass over an element without producing its value
+    fn skip(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError>;
+
+    /// Pass over the given number of bits of uncompressed data
+    fn skip_bits(&mut self, nbits: usize) -> Result<(), BufrKitError>;
+
+    /// Start the members of a delayed repetition, which are only present once in the data
+    fn start_repetition(&mut self);
+
+    /// End the members of a delayed repetition, which are repeated `count` times
+    fn end_repetition(&mut self, count: usize) -> Result<(), BufrKitError>;
+
+    /// Read or write a delayed replication factor and return the replication count
+    fn replication_factor(&mut self, spec: &ValueSpec) -> Result<usize, BufrKitError>;
+}
+
+impl<'a, 'b> ValueStream for PayloadReader<'a, 'b> {
+    fn n_subsets(&self) -> usize {
+        PayloadReader::n_subsets(self)
+    }
+
+    fn is_compressed(&self) -> bool {
+        PayloadReader::is_compressed(self)
+    }
+
+    fn start_subset(&mut self, index: usize) {
+        PayloadReader::start_subset(self, index)
+    }
+
+    fn value(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        self.read(spec)
+    }
+
+    fn skip(&mut self, spec: &ValueSpec) -> Result<(), BufrKitError> {
+        PayloadReader::skip(self, spec)
+    }
+
+    fn skip_bits(&mut self, nbits: usize) -> Result<(), BufrKitError> {
+        PayloadReader::skip_bits(self, nbits)
+    }
+
+    fn start_repetition(&mut self) {
+        PayloadReader::start_repetition(self)
+    }
+
+    fn end_repetition(&mut self, count: usize) -> Result<(), BufrKitError> {
+        PayloadReader::end_repetition(self, count)
+    }
+
+    fn replication_factor(&mut self, spec: &ValueSpec) -> Result<usize, BufrKitError> {
+        self.read_replication_factor(spec)
+    }
+}
+
+/// A template lowered into a flat list of instructions, so that decoding and
+/// encoding do not walk the tree once per subset
+#[derive(Debug, Clone)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn compile(template: &Template) -> Result<Program, BufrKitError> {
+        let mut instructions = Vec::new();
+        compile_children(template.root().children(), &mut Some(OperatorState::default()), &mut instructions)?;
+        Ok(Program { instructions })
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// A program that only produces the values of the given descriptors. Elements
+    /// whose widths are known are skipped together in uncompressed data.
+    pub fn project(&self, ids: &HashSet<Fxy>, compressed: bool) -> Program {
+        let mut instructions = Vec::with_capacity(self.instructions.len());
+        // Where the replications being copied begin in the new program
+        let mut begins = Vec::new();
+        for instruction in &self.instructions {
+            match instruction {
+                Instruction::Value(operand) if !ids.contains(&operand.id()) => match (operand, instructions.last_mut()) {
+                    (Operand::Resolved(spec), Some(Instruction::SkipBits(nbits))) if !compressed => *nbits += spec.nbits,
+                    (Operand::Resolved(spec), _) if !compressed => instructions.push(Instruction::SkipBits(spec.nbits)),
+                    _ => instructions.push(Instruction::Skip(operand.clone())),
+                },
+                Instruction::BeginReplication { .. } | Instruction::BeginDelayedReplication { .. } => {
+                    begins.push(instructions.len());
+                    instructions.push(instruction.clone());
+                }
+                Instruction::EndReplication => {
+                    let end_index = instructions.len();
+                    instructions.push(Instruction::EndReplication);
+                    match &mut instructions[begins.pop().expect("replication has begun")] {
+                        Instruction::BeginReplication { end, .. } | Instruction::BeginDelayedReplication { end, .. } => *end = end_index,
+                        _ => unreachable!("replication begins at its begin index"),
+                    }
+                }
+                _ => instructions.push(instruction.clone()),
+            }
+        }
+        Program { instructions }
+    }
+
+    /// Run the program once per subset, or once for all subsets of compressed data
+    pub fn run<S: ValueStream>(&self, stream: &mut S) -> Result<(), BufrKitError> {
+        if stream.is_compressed() {
+            self.run_once(stream)
+        } else {
+            for i in 0..stream.n_subsets() {
+                stream.start_subset(i);
+                self.run_once(stream)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn run_once<S: ValueStream>(&self, stream: &mut S) -> Result<(), BufrKitError> {
+        let mut operators = OperatorState::default();
+        // Start of the body and remaining repetitions of each replication being run, and
+        // the count of those that are delayed repetitions
+        let mut loops: Vec<(usize, usize, Option<usize>)> = Vec::new();
+        let mut pc = 0;
+        while pc < self.instructions.len() {
+            let (count, end, repeated) = match &self.instructions[pc] {
+                Instruction::Value(operand) => {
+                    stream.value(&resolve(operand, &operators)?)?;
+                    pc += 1;
+                    continue;
+                }
+                Instruction::Skip(operand) => {
+                    stream.skip(&resolve(operand, &operators)?)?;
+                    pc += 1;
+                    continue;
+                }
+                Instruction::SkipBits(nbits) => {
+                    stream.skip_bits(*nbits)?;
+                    pc += 1;
+                    continue;
+                }
+                Instruction::Operator(id) => {
+                    if let Some(n_chars) = operators.apply(*id)? {
+                        stream.value(&ValueSpec { id: *id, kind: ValueKind::Text, nbits: n_chars * 8 })?;
+                    }
+                    pc += 1;
+                    continue;
+                }
+                Instruction::EndReplication => {
+                    let top = loops.last_mut().expect("replication has begun");
+                    top.1 -= 1;
+                    if top.1 > 0 {
+                        pc = top.0;
+                    } else {
+                        if let Some(count) = top.2 {
+                            stream.end_repetition(count)?;
+                        }
+                        loops.pop();
+                        pc += 1;
+                    }
+                    continue;
+                }
+                Instruction::BeginReplication { count, end } => (*count, *end, false),
+                Instruction::BeginDelayedReplication { factor, end } => {
+                    let count = stream.replication_factor(&resolve(factor, &operators)?)?;
+                    (count, *end, is_repetition_factor(factor.id()))
+                }
+            };
+            if count == 0 {
+                pc = end + 1;
+            } else if repeated {
+                stream.start_repetition();
+                loops.push((pc + 1, 1, Some(count)));
+                pc += 1;
+            } else {
+                loops.push((pc + 1, count, None));
+                pc += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Operand {
+    pub fn id(&self) -> Fxy {
+        match self {
+            Operand::Resolved(spec) => spec.id,
+            Operand::Deferred(descriptor) => descriptor.id,
+        }
+    }
+}
+
+fn resolve(operand: &Operand, operators: &OperatorState) -> Result<ValueSpec, BufrKitError> {
+    match operand {
+        Operand::Resolved(spec) => Ok(*spec),
+        Operand::Deferred(descriptor) => operators.spec(descriptor),
+    }
+}
+
+/// `state` holds the operators in effect, or None where they depend on replication counts
+fn compile_children(children: Children<'_>,
+                    state: &mut Option<OperatorState>,
+                    instructions: &mut Vec<Instruction>) -> Result<(), BufrKitError> {
+    for node in children.iter() {
+        compile_node(node, state, instructions)?;
+    }
+    Ok(())
+}
+
+fn compile_node(node: NodeRef<'_>,
+                state: &mut Option<OperatorState>,
+                instructions: &mut Vec<Instruction>) -> Result<(), BufrKitError> {
+    match node.descriptor() {
+        Descriptor::Element(descriptor) => instructions.push(Instruction::Value(operand(descriptor, state))),
+        Descriptor::Operator(descriptor) => {
+            // Unsupported operators fail when the program runs, as they do with the visitor
+            *state = state.and_then(|mut s| s.apply(descriptor.id).ok().map(|_| s));
+            instructions.push(Instruction::Operator(descriptor.id));
+        }
+        Descriptor::Sequence(_) => compile_children(node.children(), state, instructions)?,
+        Descriptor::Replication(descriptor) => {
+            let children = node.children();
+            let (begin, members) = if descriptor.id.y() == 0 {
+                let factor = match children.get(0).map(|node| node.descriptor()) {
+                    Some(Descriptor::Element(factor)) => factor,
+                    _ => return Err(BufrKitError::InvalidDescriptor(
+                        format!("{}: expected an element descriptor as replication factor", descriptor.id))),
+                };
+                (Instruction::BeginDelayedReplication { factor: operand(factor, state), end: 0 }, children.skip(1))
+            } else {
+                (Instruction::BeginReplication { count: descriptor.id.y() as usize, end: 0 }, children)
+            };
+            let begin_index = instructions.len();
+            instructions.push(begin);
+            // Operators in the members change what later repetitions see
+            if has_operators(members) {
+                *state = None;
+            }
+            compile_children(members, state, instructions)?;
+            let end_index = instructions.len();
+            instructions.push(Instruction::EndReplication);
+            match &mut instructions[begin_index] {
+                Instruction::BeginReplication { end, .. } | Instruction::BeginDelayedReplication { end, .. } => *end = end_index,
+                _ => unreachable!("replication begins at begin_index"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn operand(descriptor: &ElementDescriptor, state: &Option<OperatorState>) -> Operand {
+    match state.map(|s| s.spec(descriptor)) {
+        Some(Ok(spec)) => Operand::Resolved(spec),
+        // Invalid widths are reported when the program runs, if the element is ever read
+        _ => Operand::Deferred(descriptor.clone()),
+    }
+}
+
+fn has_operators(children: Children<'_>) -> bool {
+    children.iter().any(|node| matches!(node.descriptor(), Descriptor::Operator(_)) || has_operators(node.children()))
+}
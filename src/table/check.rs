@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::table::descriptor::Fxy;
+use crate::table::table::{TableGroup, TableD};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub id: Fxy,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(id: Fxy, message: String) -> Self {
+        Problem { id, message }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.message)
+    }
+}
+
+/// Check the table group for dangling sequence members, recursive sequences,
+/// data widths that do not fit their values, reference values that take values out of
+/// range and missing code tables.
+/// Problems are ordered by descriptor ID.
+pub fn check_table_group(table_group: &TableGroup) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_sequence_members(table_group, &mut problems);
+    check_recursive_sequences(table_group.table_d(), &mut problems);
+    check_element_widths(table_group, &mut problems);
+    check_code_and_flag(table_group, &mut problems);
+    problems.sort_by_key(|p| p.id);
+    problems
+}
+
+fn check_sequence_members(table_group: &TableGroup, problems: &mut Vec<Problem>) {
+    for id in sorted(table_group.table_d().iter().map(|(id, _)| *id)) {
+        let dentry = table_group.table_d().get(id).unwrap();
+        for member in dentry.members.iter() {
+            if let Err(e) = table_group.lookup(*member) {
+                problems.push(Problem::new(
+                    id, format!("member {} cannot be resolved: {}", member, e)));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Visiting,
+    Done,
+}
+
+fn check_recursive_sequences(table_d: &TableD, problems: &mut Vec<Problem>) {
+    let mut marks = HashMap::new();
+    for id in sorted(table_d.iter().map(|(id, _)| *id)) {
+        visit_sequence(table_d, id, &mut marks, &mut vec![], problems);
+    }
+}
+
+fn visit_sequence(table_d: &TableD,
+                  id: Fxy,
+                  marks: &mut HashMap<Fxy, Mark>,
+                  path: &mut Vec<Fxy>,
+                  problems: &mut Vec<Problem>) {
+    match marks.get(&id) {
+        Some(Mark::Done) => return,
+        Some(Mark::Visiting) => {
+            let start = path.iter().position(|x| *x == id).unwrap();
+            let cycle: Vec<String> = path[start..].iter().chain(Some(&id))
+                .map(|x| x.to_string()).collect();
+            problems.push(Problem::new(id, format!("recursive sequence: {}", cycle.join(" -> "))));
+            return;
+        }
+        None => {}
+    }
+    // Dangling sequences are reported as unresolvable members
+    let dentry = match table_d.get(id) {
+        Some(dentry) => dentry,
+        None => return,
+    };
+    marks.insert(id, Mark::Visiting);
+    path.push(id);
+    for member in dentry.members.iter().filter(|m| m.f() == 3) {
+        visit_sequence(table_d, *member, marks, path, problems);
+    }
+    path.pop();
+    marks.insert(id, Mark::Done);
+}
+
+fn check_element_widths(table_group: &TableGroup, problems: &mut Vec<Problem>) {
+    let table_b = table_group.table_b();
+    for id in sorted(table_b.iter().map(|(id, _)| *id)) {
+        let bentry = table_b.get(id).unwrap();
+        if bentry.nbits <= 0 {
+            problems.push(Problem::new(id, format!("data width must be positive, got {}", bentry.nbits)));
+        } else if bentry.unit == "CCITT IA5" {
+            if bentry.nbits % 8 != 0 {
+                problems.push(Problem::new(
+                    id, format!("character data width {} is not a multiple of 8", bentry.nbits)));
+            }
+        } else if bentry.nbits > 32 {
+            problems.push(Problem::new(
+                id, format!("data width {} exceeds the maximum of 32 bits", bentry.nbits)));
+        } else {
+            check_value_range(id, bentry.refval as i64, bentry.nbits as u32, problems);
+        }
+        if is_code_or_flag(&bentry.unit) && (bentry.scale != 0 || bentry.refval != 0) {
+            problems.push(Problem::new(
+                id, format!("{} must have zero scale and reference value, got scale {} and refval {}",
+                            bentry.unit, bentry.scale, bentry.refval)));
+        }
+    }
+}
+
+/// Values are `refval` plus a raw value of up to `2^nbits - 1`. Reference values take sign
+/// and magnitude in 32 bits, as when redefined by operator 203YYY, and values must fit in
+/// 32 bits, signed if the reference value is negative.
+fn check_value_range(id: Fxy, refval: i64, nbits: u32, problems: &mut Vec<Problem>) {
+    let max = if refval < 0 { i32::MAX as i64 } else { u32::MAX as i64 };
+    if refval.abs() > i32::MAX as i64 {
+        problems.push(Problem::new(
+            id, format!("reference value {} cannot be represented in 32 bits", refval)));
+    } else if refval + (1i64 << nbits) - 1 > max {
+        problems.push(Problem::new(
+            id, format!("values of {} bits with reference value {} exceed the 32 bits range", nbits, refval)));
+    }
+}
+
+fn check_code_and_flag(table_group: &TableGroup, problems: &mut Vec<Problem>) {
+    let table_b = table_group.table_b();
+    let cnf = table_group.code_and_flag();
+    for id in sorted(table_b.iter().map(|(id, _)| *id)) {
+        let bentry = table_b.get(id).unwrap();
+        if !is_code_or_flag(&bentry.unit) {
+            continue;
+        }
+        let entries = match cnf.get(id) {
+            Some(entries) => entries,
+            None => {
+                if bentry.unit == "CODE TABLE" {
+                    problems.push(Problem::new(id, "code table entries not found".to_owned()));
+                }
+                continue;
+            }
+        };
+        for value in sorted(entries.keys().cloned()) {
+            if bentry.unit == "CODE TABLE" {
+                if value < 0 || (bentry.nbits < 64 && value >= 1 << bentry.nbits) {
+                    problems.push(Problem::new(
+                        id, format!("code value {} does not fit in {} bits", value, bentry.nbits)));
+                }
+            } else if value < 1 || value > bentry.nbits {
+                problems.push(Problem::new(
+                    id, format!("flag bit {} is outside of the {} bits data width", value, bentry.nbits)));
+            }
+        }
+    }
+    for id in sorted(cnf.iter().map(|(id, _)| *id)) {
+        if table_b.get(id).is_none() {
+            problems.push(Problem::new(id, "code and flag entries for an unknown element".to_owned()));
+        }
+    }
+}
+
+fn is_code_or_flag(unit: &str) -> bool {
+    unit == "CODE TABLE" || unit == "FLAG TABLE"
+}
+
+fn sorted<T: Ord>(items: impl Iterator<Item=T>) -> Vec<T> {
+    let mut items: Vec<T> = items.collect();
+    items.sort_unstable();
+    items
+}
@@ -1,30 +1,121 @@
 use std::fmt::{Display, Formatter, Error, Result};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use crate::table::table::{BEntry, DEntry};
+use crate::BufrKitError;
+
+/// A validated descriptor ID made of F (2 bits), X (6 bits) and Y (8 bits).
+/// It is stored as the decimal number FXXYYY so that IDs order the same way
+/// as they are listed in the tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Fxy(u32);
+
+impl Fxy {
+    pub fn new(f: u8, x: u8, y: u16) -> std::result::Result<Fxy, BufrKitError> {
+        if f > 3 || x > 63 || y > 255 {
+            return Err(BufrKitError::InvalidDescriptor(
+                format!("{}-{:02}-{:03}: not a valid descriptor ID", f, x, y)));
+        }
+        Ok(Fxy(f as u32 * 100_000 + x as u32 * 1000 + y as u32))
+    }
+
+    pub fn f(&self) -> u8 {
+        (self.0 / 100_000) as u8
+    }
+
+    pub fn x(&self) -> u8 {
+        ((self.0 / 1000) % 100) as u8
+    }
 
-pub trait Fxy {
-    fn id(&self) -> isize;
-    fn f(&self) -> isize {
-        self.id() / 100_000
+    pub fn y(&self) -> u16 {
+        (self.0 % 1000) as u16
     }
-    fn x(&self) -> isize {
-        (self.id() / 1000) % 100
+
+    /// F and X combined, e.g. 12 for 012101
+    pub fn fx(&self) -> u16 {
+        (self.0 / 1000) as u16
     }
-    fn y(&self) -> isize {
-        self.id() % 1000
+}
+
+impl Display for Fxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:06}", self.0)
     }
-    fn fx(&self) -> isize {
-        self.id() / 1000
+}
+
+impl TryFrom<u32> for Fxy {
+    type Error = BufrKitError;
+
+    fn try_from(id: u32) -> std::result::Result<Self, Self::Error> {
+        if id >= 400_000 {
+            return Err(BufrKitError::InvalidDescriptor(format!("{}: not a valid descriptor ID", id)));
+        }
+        Fxy::new((id / 100_000) as u8, ((id / 1000) % 100) as u8, (id % 1000) as u16)
     }
-    fn as_string(&self) -> String {
-        return format!("{:06}", self.id());
+}
+
+impl From<Fxy> for u32 {
+    fn from(id: Fxy) -> Self {
+        id.0
     }
 }
 
-pub type ID = isize;
+/// Accepts "012101", "12101", "0 12 101" and "0-12-101"
+impl FromStr for Fxy {
+    type Err = BufrKitError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || BufrKitError::InvalidDescriptor(format!("{}: not a valid descriptor ID", s));
+        let s = s.trim();
+        let parts: Vec<&str> = s.split([' ', '-']).filter(|p| !p.is_empty()).collect();
+        match parts.len() {
+            1 if s.len() <= 6 && s.bytes().all(|b| b.is_ascii_digit()) => {
+                Fxy::try_from(s.parse::<u32>().map_err(|_| invalid())?)
+            }
+            3 if parts.iter().all(|p| p.bytes().all(|b| b.is_ascii_digit())) => {
+                Fxy::new(parts[0].parse().map_err(|_| invalid())?,
+                         parts[1].parse().map_err(|_| invalid())?,
+                         parts[2].parse().map_err(|_| invalid())?)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
 
-impl Fxy for ID {
-    fn id(&self) -> isize {
-        *self
+impl Serialize for Fxy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fxy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FxyVisitor;
+
+        impl<'de> de::Visitor<'de> for FxyVisitor {
+            type Value = Fxy;
+
+            fn expecting(&self, f: &mut Formatter) -> Result {
+                write!(f, "a descriptor ID")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Fxy, E> {
+                v.parse::<Fxy>().map_err(|e| E::custom(e))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Fxy, E> {
+                let v = u32::try_from(v).map_err(|_| E::custom(format!("{}: not a valid descriptor ID", v)))?;
+                Fxy::try_from(v).map_err(|e| E::custom(e))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Fxy, E> {
+                let v = u64::try_from(v).map_err(|_| E::custom(format!("{}: not a valid descriptor ID", v)))?;
+                self.visit_u64(v)
+            }
+        }
+
+        deserializer.deserialize_any(FxyVisitor)
     }
 }
 
@@ -36,8 +127,8 @@ pub enum Descriptor {
     Sequence(SequenceDescriptor),
 }
 
-impl Fxy for Descriptor {
-    fn id(&self) -> ID {
+impl Descriptor {
+    pub fn id(&self) -> Fxy {
         match self {
             Descriptor::Element(d) => d.id,
             Descriptor::Replication(d) => d.id,
@@ -58,9 +149,9 @@ impl Display for Descriptor {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ElementDescriptor {
-    pub id: ID,
+    pub id: Fxy,
     pub name: String,
     pub unit: String,
     pub scale: isize,
@@ -70,59 +161,41 @@ pub struct ElementDescriptor {
 
 impl Display for ElementDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{} {}", self.id.as_string(), self.name)
+        write!(f, "{} {}", self.id, self.name)
     }
 }
 
 #[derive(Debug)]
 pub struct ReplicationDescriptor {
-    pub id: ID,
-}
-
-impl Fxy for ReplicationDescriptor {
-    fn id(&self) -> isize {
-        self.id
-    }
+    pub id: Fxy,
 }
 
 impl Display for ReplicationDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{} {}", self.id.as_string(), if self.y() == 0 {"DELAYED REPLICATION"} else {"FIXED REPLICATION"})
+        write!(f, "{} {}", self.id, if self.id.y() == 0 {"DELAYED REPLICATION"} else {"FIXED REPLICATION"})
     }
 }
 
 #[derive(Debug)]
 pub struct OperatorDescriptor {
-    pub id: ID,
+    pub id: Fxy,
     pub name: String,
 }
 
 impl Display for OperatorDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{} {}", self.id.as_string(), self.name)
-    }
-}
-
-impl Fxy for OperatorDescriptor {
-    fn id(&self) -> isize {
-        self.id
+        write!(f, "{} {}", self.id, self.name)
     }
 }
 
 #[derive(Debug)]
 pub struct SequenceDescriptor {
-    pub id: ID,
+    pub id: Fxy,
     pub name: String,
 }
 
-impl Fxy for SequenceDescriptor {
-    fn id(&self) -> isize {
-        self.id
-    }
-}
-
 impl Display for SequenceDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{} {}", self.id.as_string(), self.name)
+        write!(f, "{} {}", self.id, self.name)
     }
 }
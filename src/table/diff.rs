@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use crate::table::descriptor::Fxy;
+use crate::table::table::{TableGroup, BEntry, DEntry};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Difference of a single table entry. Added and removed entries carry only their
+/// name while changed entries list the fields that differ.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntryDiff {
+    pub id: Fxy,
+    pub kind: DiffKind,
+    pub name: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TableGroupDiff {
+    pub old: String,
+    pub new: String,
+    pub table_b: Vec<EntryDiff>,
+    pub table_d: Vec<EntryDiff>,
+    pub code_and_flag: Vec<EntryDiff>,
+}
+
+impl TableGroupDiff {
+    pub fn is_empty(&self) -> bool {
+        self.table_b.is_empty() && self.table_d.is_empty() && self.code_and_flag.is_empty()
+    }
+}
+
+/// Compare Table B, Table D and code and flag tables of two table groups
+pub fn diff_table_groups(old: &TableGroup, new: &TableGroup) -> TableGroupDiff {
+    let table_b = diff_by_id(
+        old.table_b().iter().collect(),
+        new.table_b().iter().collect(),
+        |bentry| bentry.name.clone(),
+        diff_bentry,
+    );
+    let table_d = diff_by_id(
+        old.table_d().iter().collect(),
+        new.table_d().iter().collect(),
+        |dentry| dentry.name.clone(),
+        diff_dentry,
+    );
+    let name_of_cnf = |id: Fxy| new.table_b().get(id).or_else(|| old.table_b().get(id))
+        .map_or_else(String::new, |bentry| bentry.name.clone());
+    let mut code_and_flag = diff_by_id(
+        old.code_and_flag().iter().collect(),
+        new.code_and_flag().iter().collect(),
+        |_| String::new(),
+        diff_cnf,
+    );
+    code_and_flag.iter_mut().for_each(|d| d.name = name_of_cnf(d.id));
+
+    TableGroupDiff {
+        old: old.id().to_string(),
+        new: new.id().to_string(),
+        table_b,
+        table_d,
+        code_and_flag,
+    }
+}
+
+fn diff_by_id<T, N, C>(old: HashMap<&Fxy, &T>,
+                       new: HashMap<&Fxy, &T>,
+                       name_of: N,
+                       changes_of: C) -> Vec<EntryDiff>
+    where N: Fn(&T) -> String,
+          C: Fn(&T, &T) -> Vec<FieldChange> {
+    let mut ids: BTreeMap<Fxy, (Option<&T>, Option<&T>)> = BTreeMap::new();
+    for (id, entry) in old.into_iter() {
+        ids.entry(*id).or_default().0 = Some(entry);
+    }
+    for (id, entry) in new.into_iter() {
+        ids.entry(*id).or_default().1 = Some(entry);
+    }
+
+    let mut diffs = Vec::new();
+    for (id, entries) in ids.into_iter() {
+        let (kind, name, changes) = match entries {
+            (Some(o), Some(n)) => {
+                let changes = changes_of(o, n);
+                if changes.is_empty() {
+                    continue;
+                }
+                (DiffKind::Changed, name_of(n), changes)
+            }
+            (None, Some(n)) => (DiffKind::Added, name_of(n), vec![]),
+            (Some(o), None) => (DiffKind::Removed, name_of(o), vec![]),
+            (None, None) => continue,
+        };
+        diffs.push(EntryDiff { id, kind, name, changes });
+    }
+    diffs
+}
+
+fn diff_bentry(old: &BEntry, new: &BEntry) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    push_if_changed(&mut changes, "name", &old.name, &new.name);
+    push_if_changed(&mut changes, "unit", &old.unit, &new.unit);
+    push_if_changed(&mut changes, "scale", &old.scale, &new.scale);
+    push_if_changed(&mut changes, "refval", &old.refval, &new.refval);
+    push_if_changed(&mut changes, "nbits", &old.nbits, &new.nbits);
+    changes
+}
+
+fn diff_dentry(old: &DEntry, new: &DEntry) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    push_if_changed(&mut changes, "name", &old.name, &new.name);
+    changes.extend(diff_members(&old.members, &new.members));
+    changes
+}
+
+/// Members added, removed or replaced, keeping the longest run of members common to both
+/// lists. Each change is named after the 1-based position of its member, in the new list
+/// unless the member was removed.
+fn diff_members(old: &[Fxy], new: &[Fxy]) -> Vec<FieldChange> {
+    // Length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let member = |position: usize, old: Option<&Fxy>, new: Option<&Fxy>| FieldChange {
+        field: format!("member {}", position + 1),
+        old: old.map(|id| id.to_string()),
+        new: new.map(|id| id.to_string()),
+    };
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && j < new.len() && common[i + 1][j + 1] == common[i][j] {
+            changes.push(member(j, old.get(i), new.get(j)));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            changes.push(member(j, None, new.get(j)));
+            j += 1;
+        } else {
+            changes.push(member(i, old.get(i), None));
+            i += 1;
+        }
+    }
+    changes
+}
+
+fn diff_cnf(old: &HashMap<isize, String>, new: &HashMap<isize, String>) -> Vec<FieldChange> {
+    let mut values: Vec<&isize> = old.keys().chain(new.keys()).collect();
+    values.sort_unstable();
+    values.dedup();
+    values.into_iter()
+        .filter(|value| old.get(value) != new.get(value))
+        .map(|value| FieldChange {
+            field: value.to_string(),
+            old: old.get(value).cloned(),
+            new: new.get(value).cloned(),
+        })
+        .collect()
+}
+
+fn push_if_changed<T: PartialEq + ToString>(changes: &mut Vec<FieldChange>, field: &str, old: &T, new: &T) {
+    if old != new {
+        changes.push(FieldChange {
+            field: field.to_owned(),
+            old: Some(old.to_string()),
+            new: Some(new.to_string()),
+        });
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let old = self.old.as_deref().unwrap_or("(none)");
+        let new = self.new.as_deref().unwrap_or("(none)");
+        write!(f, "{}: {} -> {}", self.field, old, new)
+    }
+}
+
+impl fmt::Display for EntryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.kind {
+            DiffKind::Added => '+',
+            DiffKind::Removed => '-',
+            DiffKind::Changed => '~',
+        };
+        write!(f, "{} {}", sign, self.id)?;
+        if !self.name.is_empty() {
+            write!(f, " {}", self.name)?;
+        }
+        for change in self.changes.iter() {
+            write!(f, "\n      {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TableGroupDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "--- {}", self.old)?;
+        write!(f, "+++ {}", self.new)?;
+        for (title, diffs) in [("Table B", &self.table_b),
+            ("Table D", &self.table_d),
+            ("Code and flag tables", &self.code_and_flag)].iter() {
+            if diffs.is_empty() {
+                continue;
+            }
+            write!(f, "\n{}", title)?;
+            for diff in diffs.iter() {
+                write!(f, "\n  {}", diff)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
+#[allow(clippy::module_inception)]
 pub mod table;
 pub mod descriptor;
 pub mod template;
+pub mod check;
+pub mod diff;
+pub mod search;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
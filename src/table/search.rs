@@ -0,0 +1,67 @@
+use std::collections::{BTreeSet, HashMap};
+use regex::{Regex, RegexBuilder};
+use crate::table::descriptor::Fxy;
+use crate::table::table::TableGroup;
+use crate::BufrKitError;
+
+/// Match descriptor names either by keywords, all of which must appear in the name,
+/// or by a regular expression. Both are case insensitive.
+pub enum NameMatcher {
+    Keywords(Vec<String>),
+    Regex(Regex),
+}
+
+impl NameMatcher {
+    pub fn keywords(s: &str) -> Self {
+        NameMatcher::Keywords(s.split_whitespace().map(|w| w.to_uppercase()).collect())
+    }
+
+    pub fn regex(s: &str) -> Result<Self, BufrKitError> {
+        Ok(NameMatcher::Regex(RegexBuilder::new(s).case_insensitive(true).build()?))
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            NameMatcher::Keywords(words) => {
+                let name = name.to_uppercase();
+                words.iter().all(|w| name.contains(w.as_str()))
+            }
+            NameMatcher::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+/// Search Table B and Table D entries by name, ordered by descriptor ID
+pub fn search<'a>(table_group: &'a TableGroup, matcher: &NameMatcher) -> Vec<(Fxy, &'a str)> {
+    let mut found: Vec<(Fxy, &str)> = table_group.table_b().iter()
+        .map(|(id, bentry)| (*id, bentry.name.as_str()))
+        .chain(table_group.table_d().iter().map(|(id, dentry)| (*id, dentry.name.as_str())))
+        .filter(|(_, name)| matcher.is_match(name))
+        .collect();
+    found.sort_unstable_by_key(|(id, _)| *id);
+    found
+}
+
+/// Find all Table D sequences that contain the given descriptor, either as a direct
+/// member or through nested sequences. Each sequence is paired with whether it
+/// contains the descriptor directly.
+pub fn used_by(table_group: &TableGroup, id: Fxy) -> Vec<(Fxy, bool)> {
+    let mut parents: HashMap<Fxy, BTreeSet<Fxy>> = HashMap::new();
+    for (sequence_id, dentry) in table_group.table_d().iter() {
+        for member in dentry.members.iter() {
+            parents.entry(*member).or_default().insert(*sequence_id);
+        }
+    }
+
+    let direct = parents.get(&id).cloned().unwrap_or_default();
+    let mut found = BTreeSet::new();
+    let mut pending: Vec<Fxy> = direct.iter().cloned().collect();
+    while let Some(sequence_id) = pending.pop() {
+        if found.insert(sequence_id) {
+            if let Some(p) = parents.get(&sequence_id) {
+                pending.extend(p.iter());
+            }
+        }
+    }
+    found.into_iter().map(|x| (x, direct.contains(&x))).collect()
+}
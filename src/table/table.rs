@@ -1,10 +1,15 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::path::{MAIN_SEPARATOR, Path, PathBuf};
-use crate::table::descriptor::{ID, Fxy};
+use crate::table::descriptor::Fxy;
 use std::fs::File;
+use std::io::BufReader;
+use serde::de::DeserializeOwned;
 use crate::BufrKitError;
-use std::sync::{RwLock, Arc};
+use std::sync::{RwLock, Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::table::template::{Template, TemplateCache};
+use crate::payload::program::Program;
 
 pub enum Entry<'a> {
     B(&'a BEntry),
@@ -26,16 +31,16 @@ pub struct BEntry {
 }
 
 pub struct REntry {
-    id: ID,
+    id: Fxy,
 }
 
 impl REntry {
-    pub fn n_members(&self) -> isize {
-        self.id.x()
+    pub fn n_members(&self) -> usize {
+        self.id.x() as usize
     }
 
-    pub fn n_repeats(&self) -> isize {
-        self.id.y()
+    pub fn n_repeats(&self) -> usize {
+        self.id.y() as usize
     }
 }
 
@@ -47,30 +52,198 @@ pub struct CEntry {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DEntry {
     pub name: String,
-    pub members: Vec<isize>,
+    pub members: Vec<Fxy>,
+}
+
+/// Hit, miss and eviction counters of a `TableGroupManager` or `TemplateCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub size: usize,
+}
+
+struct CacheEntry {
+    slot: Arc<Slot>,
+    last_used: AtomicU64,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Read without locking once loaded
+    table_group: OnceLock<Arc<TableGroup>>,
+    // Held while the table group loads so that concurrent requests for the same id
+    // wait for a single load instead of each loading their own copy.
+    loading: Mutex<()>,
 }
 
 pub struct TableGroupManager {
-    cache: RwLock<HashMap<TableGroupId, Arc<TableGroup>>>
+    cache: RwLock<HashMap<TableGroupId, CacheEntry>>,
+    capacity: Option<usize>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    templates: TemplateCache,
+}
+
+impl Default for TableGroupManager {
+    fn default() -> Self {
+        TableGroupManager::new()
+    }
 }
 
 impl TableGroupManager {
     pub fn new() -> Self {
         TableGroupManager {
             cache: RwLock::new(HashMap::new()),
+            capacity: None,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            templates: TemplateCache::new(),
+        }
+    }
+
+    /// Create a manager that keeps at most `capacity` table groups and evicts
+    /// the least recently used one when the limit is exceeded
+    pub fn with_capacity(capacity: usize) -> Self {
+        TableGroupManager {
+            capacity: Some(capacity.max(1)),
+            ..TableGroupManager::new()
         }
     }
 
     pub fn get_table_group(&self, table_group_id: &TableGroupId) -> Result<Arc<TableGroup>, BufrKitError> {
-        if !self.cache.read().unwrap().contains_key(table_group_id) {
-            let mut cache = self.cache.write().unwrap();
-            cache.insert(table_group_id.clone(), Arc::new(TableGroup::load(table_group_id)?));
+        let slot = self.slot_of(table_group_id);
+        if let Some(table_group) = slot.table_group.get() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(table_group.clone());
+        }
+        let loading = slot.loading.lock().unwrap();
+        if let Some(table_group) = slot.table_group.get() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(table_group.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        match TableGroup::load(table_group_id) {
+            Ok(table_group) => {
+                let table_group = slot.table_group.get_or_init(|| Arc::new(table_group)).clone();
+                drop(loading);
+                self.enforce_capacity(table_group_id);
+                Ok(table_group)
+            }
+            Err(e) => {
+                drop(loading);
+                // Do not keep empty slots around for ids that cannot be loaded
+                let mut cache = self.cache.write().unwrap();
+                if cache.get(table_group_id).is_some_and(|entry| Arc::ptr_eq(&entry.slot, &slot)) {
+                    cache.remove(table_group_id);
+                }
+                Err(e)
+            }
         }
-        Ok(self.cache.read().unwrap().get(&table_group_id).unwrap().clone())
     }
 
+    /// Get the expanded template of the descriptors from the template cache
+    pub fn get_template(&self,
+                        table_group: &TableGroup,
+                        unexpanded_descriptors: &[Fxy],
+                        max_depth: usize) -> Result<Arc<Template>, BufrKitError> {
+        self.templates.get_template(table_group, unexpanded_descriptors, max_depth)
+    }
+
+    /// Get the expanded template of the descriptors and its compiled program from the
+    /// template cache
+    pub fn get_compiled(&self,
+                        table_group: &TableGroup,
+                        unexpanded_descriptors: &[Fxy],
+                        max_depth: usize) -> Result<(Arc<Template>, Arc<Program>), BufrKitError> {
+        self.templates.get_compiled(table_group, unexpanded_descriptors, max_depth)
+    }
+
+    pub fn template_stats(&self) -> CacheStats {
+        self.templates.stats()
+    }
+
+    /// Load the given table groups ahead of time
+    pub fn preload(&self, table_group_ids: &[TableGroupId]) -> Result<(), BufrKitError> {
+        for table_group_id in table_group_ids {
+            self.get_table_group(table_group_id)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the table group from the cache. Returns whether it was cached.
+    pub fn evict(&self, table_group_id: &TableGroupId) -> bool {
+        let removed = self.cache.write().unwrap().remove(table_group_id).is_some();
+        self.templates.evict_table_group(table_group_id);
+        if removed {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    pub fn clear(&self) {
+        let mut cache = self.cache.write().unwrap();
+        self.evictions.fetch_add(cache.len() as u64, Ordering::Relaxed);
+        cache.clear();
+        self.templates.clear();
+    }
+
+    /// Number of table groups currently loaded in the cache, not counting those still loading
     pub fn size(&self) -> usize {
-        self.cache.read().unwrap().len()
+        self.cache.read().unwrap().values()
+            .filter(|entry| entry.slot.table_group.get().is_some())
+            .count()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            size: self.size(),
+        }
+    }
+
+    fn slot_of(&self, table_group_id: &TableGroupId) -> Arc<Slot> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(entry) = self.cache.read().unwrap().get(table_group_id) {
+            entry.last_used.store(now, Ordering::Relaxed);
+            return entry.slot.clone();
+        }
+        let mut cache = self.cache.write().unwrap();
+        let entry = cache.entry(table_group_id.clone()).or_insert_with(|| CacheEntry {
+            slot: Arc::new(Slot::default()),
+            last_used: AtomicU64::new(now),
+        });
+        entry.last_used.store(now, Ordering::Relaxed);
+        entry.slot.clone()
+    }
+
+    fn enforce_capacity(&self, keep: &TableGroupId) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        let mut cache = self.cache.write().unwrap();
+        while cache.len() > capacity {
+            // Slots that are still loading are never picked for eviction
+            let victim = cache.iter()
+                .filter(|(id, entry)| *id != keep && entry.slot.table_group.get().is_some())
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(id, _)| id.clone());
+            match victim {
+                Some(id) => {
+                    cache.remove(&id);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
     }
 }
 
@@ -87,13 +260,13 @@ pub struct TableGroup {
 
 impl TableGroup {
     pub fn load(table_group_id: &TableGroupId) -> Result<TableGroup, BufrKitError> {
-        let b = TableB::load(&table_group_id)?;
-        let d = TableD::load(&table_group_id)?;
-        let cnf = CodeAndFlag::load(&table_group_id)?;
-        let ma = MetaA::load(&table_group_id)?;
-        let mb = MetaB::load(&table_group_id)?;
-        let mc = MetaC::load(&table_group_id)?;
-        let md = MetaD::load(&table_group_id)?;
+        let b = TableB::load(table_group_id)?;
+        let d = TableD::load(table_group_id)?;
+        let cnf = CodeAndFlag::load(table_group_id)?;
+        let ma = MetaA::load(table_group_id)?;
+        let mb = MetaB::load(table_group_id)?;
+        let mc = MetaC::load(table_group_id)?;
+        let md = MetaD::load(table_group_id)?;
         Ok(TableGroup { id: table_group_id.clone(), b, d, cnf, ma, mb, mc, md })
     }
 
@@ -101,30 +274,38 @@ impl TableGroup {
         &self.id
     }
 
+    pub fn table_b(&self) -> &TableB {
+        &self.b
+    }
+
+    pub fn table_d(&self) -> &TableD {
+        &self.d
+    }
+
+    pub fn code_and_flag(&self) -> &CodeAndFlag {
+        &self.cnf
+    }
+
     /// lookup descriptor with the given id
-    pub fn lookup(&self, id: ID) -> Result<Entry, BufrKitError> {
+    pub fn lookup(&self, id: Fxy) -> Result<Entry<'_>, BufrKitError> {
         match id.f() {
             0 => Ok(Entry::B(self.b.lookup(id)?)),
             1 => Ok(Entry::R(REntry { id })),
             2 => Ok(Entry::C(self.mc.lookup(id)?)),
             3 => Ok(Entry::D(self.d.lookup(id)?)),
-            _ => Err(BufrKitError {
-                message: format!("{}: not a valid form of descriptor ID", id.as_string())
-            }),
+            _ => Err(BufrKitError::InvalidDescriptor(format!("{}: not a valid form of descriptor ID", id))),
         }
     }
 
-    pub fn lookup_cnf(&self, id: ID, val: isize) -> Result<&str, BufrKitError> {
+    pub fn lookup_cnf(&self, id: Fxy, val: isize) -> Result<&str, BufrKitError> {
         if id.f() != 0 {
-            Err(BufrKitError {
-                message: format!("{}: not a valid element descriptor ID", id.as_string())
-            })
+            Err(BufrKitError::InvalidDescriptor(format!("{}: not a valid element descriptor ID", id)))
         } else {
             self.cnf.lookup(id, val)
         }
     }
 
-    pub fn lookup_meta(&self, id: ID) -> Result<&str, BufrKitError> {
+    pub fn lookup_meta(&self, id: Fxy) -> Result<&str, BufrKitError> {
         match id.f() {
             0 => self.mb.lookup(id),
             2 => {
@@ -132,9 +313,7 @@ impl TableGroup {
                 Ok(&centry.name)
             }
             3 => self.md.lookup(id),
-            _ => Err(BufrKitError {
-                message: format!("{}: metadata not found", id.as_string())
-            })
+            _ => Err(BufrKitError::MetadataNotFound(format!("{}: metadata not found", id)))
         }
     }
 
@@ -151,35 +330,38 @@ impl fmt::Display for TableGroup {
 
 // =================================================
 #[derive(Serialize, Deserialize, Debug)]
-pub struct TableB(HashMap<isize, BEntry>);
+pub struct TableB(HashMap<Fxy, BEntry>);
 
 impl TableB {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::TableB))?;
-        let t = serde_json::from_reader(ins)?;
-        Ok(t)
+        read_table(table_group_id, Name::TableB)
     }
 
-    fn lookup(&self, id: ID) -> Result<&BEntry, BufrKitError> {
-        self.0.get(&id).ok_or(BufrKitError {
-            message: format!("{} not found", id.as_string())
-        })
+    pub fn get(&self, id: Fxy) -> Option<&BEntry> {
+        self.0.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Fxy, &BEntry)> {
+        self.0.iter()
+    }
+
+    fn lookup(&self, id: Fxy) -> Result<&BEntry, BufrKitError> {
+        self.0.get(&id).ok_or(BufrKitError::DescriptorNotFound(id))
     }
 }
 
 // =================================================
 #[derive(Serialize, Deserialize, Debug)]
-pub struct TableD(HashMap<isize, DEntry>);
+pub struct TableD(HashMap<Fxy, DEntry>);
 
 impl TableD {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::TableD))?;
-        let content: HashMap<isize, (String, Vec<String>)> = serde_json::from_reader(ins)?;
+        let content: HashMap<Fxy, (String, Vec<String>)> = read_table(table_group_id, Name::TableD)?;
         let mut t = HashMap::new();
         for (k, v) in content.into_iter() {
             let mut members = Vec::new();
             for vv in v.1.into_iter() {
-                members.push(vv.parse::<isize>()?);
+                members.push(vv.parse::<Fxy>()?);
             }
             t.insert(k, DEntry {
                 name: v.0,
@@ -189,34 +371,45 @@ impl TableD {
         Ok(TableD(t))
     }
 
-    fn lookup(&self, id: ID) -> Result<&DEntry, BufrKitError> {
-        self.0.get(&id).ok_or(BufrKitError {
-            message: format!("{} not found", id.as_string())
-        })
+    pub fn get(&self, id: Fxy) -> Option<&DEntry> {
+        self.0.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Fxy, &DEntry)> {
+        self.0.iter()
+    }
+
+    fn lookup(&self, id: Fxy) -> Result<&DEntry, BufrKitError> {
+        self.0.get(&id).ok_or(BufrKitError::DescriptorNotFound(id))
     }
 }
 
-pub struct CodeAndFlag(HashMap<isize, HashMap<isize, String>>);
+pub struct CodeAndFlag(HashMap<Fxy, HashMap<isize, String>>);
 
 impl CodeAndFlag {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::CodeAndFlag))?;
-        let content: HashMap<isize, Vec<(isize, String)>> = serde_json::from_reader(ins)?;
+        let content: HashMap<Fxy, Vec<(isize, String)>> = read_table(table_group_id, Name::CodeAndFlag)?;
 
         Ok(CodeAndFlag(content.into_iter()
             .map(|(k, v)| (k, v.into_iter().collect()))
             .collect()))
     }
 
-    fn lookup(&self, id: ID, val: isize) -> Result<&str, BufrKitError> {
+    pub fn get(&self, id: Fxy) -> Option<&HashMap<isize, String>> {
+        self.0.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Fxy, &HashMap<isize, String>)> {
+        self.0.iter()
+    }
+
+    fn lookup(&self, id: Fxy, val: isize) -> Result<&str, BufrKitError> {
         if let Some(v1) = self.0.get(&id) {
             if let Some(v2) = v1.get(&val) {
                 return Ok(v2);
             }
         }
-        Err(BufrKitError {
-            message: format!("Entry not found for {} with value {}", id.as_string(), val)
-        })
+        Err(BufrKitError::CodeNotFound { id, value: val })
     }
 }
 
@@ -226,8 +419,6 @@ pub struct MetaA {
 
 impl MetaA {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::MetaA))?;
-
         #[derive(Serialize, Deserialize, Debug)]
         struct Content {
             description: String,
@@ -235,17 +426,17 @@ impl MetaA {
             entries: Vec<(String, String)>,
         }
 
-        let content: Content = serde_json::from_reader(ins)?;
+        let content: Content = read_table(table_group_id, Name::MetaA)?;
 
         let mut entries = HashMap::new();
         for entry in content.entries.into_iter() {
             if entry.0.contains(" - ") {
                 let bounds: Vec<&str> = entry.0.split(" - ").collect();
-                for i in bounds[0].parse::<isize>().unwrap()..=bounds[1].parse::<isize>().unwrap() {
+                for i in bounds[0].parse::<isize>()?..=bounds[1].parse::<isize>()? {
                     entries.insert(i, entry.1.clone());
                 }
             } else {
-                entries.insert(entry.0.parse::<isize>().unwrap(), entry.1);
+                entries.insert(entry.0.parse::<isize>()?, entry.1);
             }
         }
         Ok(MetaA { entries })
@@ -255,9 +446,7 @@ impl MetaA {
         if let Some(entry) = self.entries.get(&code) {
             Ok(entry)
         } else {
-            Err(BufrKitError {
-                message: format!("{}: data category not found", code)
-            })
+            Err(BufrKitError::MetadataNotFound(format!("{}: data category not found", code)))
         }
     }
 }
@@ -268,15 +457,13 @@ pub struct MetaB {
 
 impl MetaB {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::MetaB))?;
-
         #[derive(Serialize, Deserialize, Debug)]
         struct Content {
             description: String,
             header: (String, String, String, String),
             entries: Vec<(String, String, String, String)>,
         }
-        let content: Content = serde_json::from_reader(ins)?;
+        let content: Content = read_table(table_group_id, Name::MetaB)?;
 
         let mut entries = HashMap::new();
         for entry in content.entries.into_iter() {
@@ -288,13 +475,11 @@ impl MetaB {
         Ok(MetaB { entries })
     }
 
-    fn lookup(&self, id: ID) -> Result<&str, BufrKitError> {
+    fn lookup(&self, id: Fxy) -> Result<&str, BufrKitError> {
         if let Some(entry) = self.entries.get(&format!("{:03}", id.fx())) {
             Ok(entry)
         } else {
-            Err(BufrKitError {
-                message: format!("{}: metadata not found", id.as_string())
-            })
+            Err(BufrKitError::MetadataNotFound(format!("{}: metadata not found", id)))
         }
     }
 }
@@ -305,15 +490,13 @@ pub struct MetaC {
 
 impl MetaC {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::MetaC))?;
-
         #[derive(Serialize, Deserialize, Debug)]
         struct Content {
             description: String,
             header: (String, String, String, String, String),
             entries: Vec<(String, String, String, String, String)>,
         }
-        let content: Content = serde_json::from_reader(ins)?;
+        let content: Content = read_table(table_group_id, Name::MetaC)?;
 
         let mut entries = HashMap::new();
         for entry in content.entries.into_iter() {
@@ -328,13 +511,13 @@ impl MetaC {
         })
     }
 
-    fn lookup(&self, id: ID) -> Result<&CEntry, BufrKitError> {
-        if let Some(centry) = self.entries.get(&id.as_string()) {
+    fn lookup(&self, id: Fxy) -> Result<&CEntry, BufrKitError> {
+        if let Some(centry) = self.entries.get(&id.to_string()) {
             Ok(centry)
         } else if let Some(centry) = self.entries.get(&format!("{:03}YYY", id.fx())) {
             Ok(centry)
         } else {
-            Err(BufrKitError { message: format!("{} not found", id.as_string()) })
+            Err(BufrKitError::DescriptorNotFound(id))
         }
     }
 }
@@ -345,8 +528,6 @@ pub struct MetaD {
 
 impl MetaD {
     fn load(table_group_id: &TableGroupId) -> Result<Self, BufrKitError> {
-        let ins = File::open(table_group_id.get_table_file(Name::MetaD))?;
-
         #[derive(Serialize, Deserialize, Debug)]
         struct Content {
             description: String,
@@ -354,7 +535,7 @@ impl MetaD {
             entries: Vec<(String, String, String)>,
         }
 
-        let content: Content = serde_json::from_reader(ins)?;
+        let content: Content = read_table(table_group_id, Name::MetaD)?;
 
         let mut entries = HashMap::new();
         for entry in content.entries.into_iter() {
@@ -366,15 +547,25 @@ impl MetaD {
         Ok(MetaD { entries })
     }
 
-    fn lookup(&self, id: ID) -> Result<&str, BufrKitError> {
+    fn lookup(&self, id: Fxy) -> Result<&str, BufrKitError> {
         if let Some(entry) = self.entries.get(&format!("{:03}", id.fx())) {
             Ok(entry)
         } else {
-            Err(BufrKitError { message: format!("{} not found", id.as_string()) })
+            Err(BufrKitError::MetadataNotFound(format!("{}: metadata not found", id)))
         }
     }
 }
 
+fn read_table<T: DeserializeOwned>(table_group_id: &TableGroupId, name: Name) -> Result<T, BufrKitError> {
+    let path = table_group_id.get_table_file(name);
+    let ins = match File::open(&path) {
+        Ok(ins) => ins,
+        Err(source) => return Err(BufrKitError::TableNotFound { path, source }),
+    };
+    serde_json::from_reader(BufReader::new(ins))
+        .map_err(|e| BufrKitError::InvalidTable { path, source: Box::new(e) })
+}
+
 #[derive(Debug)]
 pub enum Name {
     TableB,
@@ -421,6 +612,26 @@ impl fmt::Display for TableGroupId {
 }
 
 impl TableGroupId {
+    /// Create the id from a table group directory, e.g. `_definitions/tables/0/0_0/25`
+    pub fn from_path(path: &str) -> Result<TableGroupId, BufrKitError> {
+        let invalid = || BufrKitError::Other(format!("{}: not a valid table group directory", path));
+        let p = Path::new(path);
+        let version = p.file_name().and_then(|s| s.to_str()).ok_or_else(invalid)?;
+        let p = p.parent().ok_or_else(invalid)?;
+        let centre = p.file_name().and_then(|s| s.to_str()).ok_or_else(invalid)?;
+        let p = p.parent().ok_or_else(invalid)?;
+        let master = p.file_name().and_then(|s| s.to_str()).ok_or_else(invalid)?;
+        let base_dir = p.parent().ok_or_else(invalid)?;
+        let (centre_number, sub_centre_number) = centre.split_once('_').ok_or_else(invalid)?;
+        Ok(TableGroupId {
+            base_dir: base_dir.to_string_lossy().into_owned(),
+            master_table_number: master.parse::<isize>()?,
+            centre_number: centre_number.parse::<isize>()?,
+            sub_centre_number: sub_centre_number.parse::<isize>()?,
+            version_number: version.parse::<isize>()?,
+        })
+    }
+
     fn get_table_file(&self, name: Name) -> PathBuf {
         let filename = format!("{}.{}", name, "json");
         let base = Path::new(&self.base_dir);
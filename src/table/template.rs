@@ -1,111 +1,405 @@
-use crate::table::descriptor::{ID, Descriptor, Fxy, ReplicationDescriptor, SequenceDescriptor, ElementDescriptor, OperatorDescriptor};
-use crate::table::table::{TableGroupManager, TableGroupId, TableGroup, Entry};
+use crate::table::descriptor::{Fxy, Descriptor, ReplicationDescriptor, SequenceDescriptor, ElementDescriptor, OperatorDescriptor};
+use crate::table::table::{CacheStats, TableGroupManager, TableGroupId, TableGroup, Entry};
+use crate::payload::program::Program;
 use crate::BufrKitError;
 use std::borrow::{Borrow, BorrowMut};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ops::Deref;
-use std::cell::{RefCell, Ref};
-use std::rc::{Rc, Weak};
 use std::fmt;
-use serde::export::Formatter;
+use std::fmt::Formatter;
 use std::slice::Iter;
 use std::iter::Peekable;
 use serde::de::Unexpected::Seq;
 
+/// A descriptor in an expanded template. Nodes live in the template's arena and
+/// refer to their parent and children by index.
 #[derive(Debug)]
 pub struct Node {
     pub descriptor: Descriptor,
-    pub parent: RefCell<Weak<Node>>,
-    pub children: RefCell<Vec<Rc<Node>>>,
+    parent: Option<usize>,
+    children: Vec<usize>,
 }
 
-impl Node {
-    pub fn accept(&self, visitor: &mut dyn Visitor) {
-        match &self.descriptor {
+/// What a visitor wants to happen after visiting a descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Visit the children, if any, then carry on with the next descriptor
+    Continue,
+    /// Do not visit the children, e.g. because the visitor has handled them itself
+    SkipChildren,
+    /// Stop visiting altogether
+    Stop,
+}
+
+pub type VisitResult = Result<Flow, BufrKitError>;
+
+/// A node together with the template it belongs to, for navigating the tree
+#[derive(Debug, Clone, Copy)]
+pub struct NodeRef<'a> {
+    template: &'a Template,
+    index: usize,
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn descriptor(&self) -> &'a Descriptor {
+        &self.template.nodes[self.index].descriptor
+    }
+
+    /// The enclosing sequence or replication, or the root for top level descriptors.
+    /// The root has no parent.
+    pub fn parent(&self) -> Option<NodeRef<'a>> {
+        self.template.nodes[self.index].parent.map(|index| NodeRef { template: self.template, index })
+    }
+
+    pub fn children(&self) -> Children<'a> {
+        Children { template: self.template, indices: &self.template.nodes[self.index].children }
+    }
+
+    /// Visit this node and its children. Returns `Flow::Stop` if the visitor stopped,
+    /// otherwise `Flow::Continue`.
+    pub fn accept(&self, visitor: &mut dyn Visitor) -> VisitResult {
+        match self.descriptor() {
             Descriptor::Element(descriptor) => {
-                visitor.visit_element_descriptor(descriptor)
+                stopped_or_continue(visitor.visit_element_descriptor(descriptor)?)
             }
             Descriptor::Replication(descriptor) => {
-                visitor.visit_replication_descriptor(descriptor, self.children.borrow());
-                if descriptor.y() == 0 {
-                    self.children.borrow().iter().enumerate().for_each(|(i, node)|
-                        if i == 0 { node.accept_replication_factor(visitor) } else { node.accept(visitor) })
-                } else {
-                    self.children.borrow().iter().for_each(|node| node.accept(visitor));
+                match visitor.visit_replication_descriptor(descriptor, self.children())? {
+                    Flow::Stop => return Ok(Flow::Stop),
+                    Flow::SkipChildren => {}
+                    Flow::Continue => {
+                        for (i, node) in self.children().iter().enumerate() {
+                            let flow = if i == 0 && descriptor.id.y() == 0 {
+                                node.accept_replication_factor(visitor)?
+                            } else {
+                                node.accept(visitor)?
+                            };
+                            if flow == Flow::Stop {
+                                return Ok(Flow::Stop);
+                            }
+                        }
+                    }
                 }
-                visitor.exit_replication_descriptor()
+                visitor.exit_replication_descriptor(descriptor)?;
+                Ok(Flow::Continue)
             }
             Descriptor::Operator(descriptor) => {
-                visitor.visit_operator_descriptor(descriptor);
+                stopped_or_continue(visitor.visit_operator_descriptor(descriptor)?)
             }
             Descriptor::Sequence(descriptor) => {
-                visitor.visit_sequence_descriptor(descriptor, self.children.borrow());
-                self.children.borrow().iter().for_each(|node| node.accept(visitor));
-                visitor.exit_sequence_descriptor()
+                match visitor.visit_sequence_descriptor(descriptor, self.children())? {
+                    Flow::Stop => return Ok(Flow::Stop),
+                    Flow::SkipChildren => {}
+                    Flow::Continue => {
+                        if self.children().accept(visitor)? == Flow::Stop {
+                            return Ok(Flow::Stop);
+                        }
+                    }
+                }
+                visitor.exit_sequence_descriptor(descriptor)?;
+                Ok(Flow::Continue)
             }
         }
     }
 
-    fn accept_replication_factor(&self, visitor: &mut dyn Visitor) {
-        if let Descriptor::Element(descriptor) = &self.descriptor {
-            visitor.visit_replication_factor(descriptor);
+    fn accept_replication_factor(&self, visitor: &mut dyn Visitor) -> VisitResult {
+        if let Descriptor::Element(descriptor) = self.descriptor() {
+            stopped_or_continue(visitor.visit_replication_factor(descriptor)?)
         } else {
-            panic!("Expected an element descriptor as replication factor, got {}", &self.descriptor)
+            Err(BufrKitError::InvalidDescriptor(
+                format!("Expected an element descriptor as replication factor, got {}", self.descriptor())))
         }
     }
 }
 
-impl fmt::Display for Node {
+impl<'a> fmt::Display for NodeRef<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.descriptor)
+        write!(f, "{}", self.descriptor())
+    }
+}
+
+fn stopped_or_continue(flow: Flow) -> VisitResult {
+    if flow == Flow::Stop { Ok(Flow::Stop) } else { Ok(Flow::Continue) }
+}
+
+/// The children of a node, in order
+#[derive(Debug, Clone, Copy)]
+pub struct Children<'a> {
+    template: &'a Template,
+    indices: &'a [usize],
+}
+
+impl<'a> Children<'a> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<NodeRef<'a>> {
+        self.indices.get(i).map(|&index| NodeRef { template: self.template, index })
+    }
+
+    /// The children from the i-th onwards
+    pub fn skip(&self, i: usize) -> Children<'a> {
+        Children { template: self.template, indices: &self.indices[i.min(self.indices.len())..] }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=NodeRef<'a>> + 'a {
+        let template = self.template;
+        self.indices.iter().map(move |&index| NodeRef { template, index })
+    }
+
+    /// Visit each child in turn. Returns `Flow::Stop` if the visitor stopped.
+    pub fn accept(&self, visitor: &mut dyn Visitor) -> VisitResult {
+        for node in self.iter() {
+            if node.accept(visitor)? == Flow::Stop {
+                return Ok(Flow::Stop);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Nesting depth of sequences and replications allowed by `Template::new`
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Number of templates kept by `TemplateCache::new`
+pub const DEFAULT_TEMPLATE_CACHE_CAPACITY: usize = 256;
+
+struct CachedTemplate {
+    template: Arc<Template>,
+    program: Arc<Program>,
+    last_used: AtomicU64,
+}
+
+/// Expanded templates and their compiled programs keyed by table group and unexpanded
+/// descriptors, so that messages sharing a template only expand and compile it once.
+/// The least recently used template is evicted when the cache is full. Finding it
+/// scans the whole cache while holding the write lock, which is cheap at the default
+/// capacity.
+pub struct TemplateCache {
+    cache: RwLock<HashMap<(TableGroupId, Vec<Fxy>), CachedTemplate>>,
+    capacity: usize,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Default for TemplateCache {
+    fn default() -> Self {
+        TemplateCache::new()
+    }
+}
+
+impl TemplateCache {
+    pub fn new() -> Self {
+        TemplateCache::with_capacity(DEFAULT_TEMPLATE_CACHE_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        TemplateCache {
+            cache: RwLock::new(HashMap::new()),
+            capacity: capacity.max(1),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the template of the descriptors, expanding it on first use. Templates
+    /// that fail to expand are not cached.
+    pub fn get_template(&self,
+                        table_group: &TableGroup,
+                        unexpanded_descriptors: &[Fxy],
+                        max_depth: usize) -> Result<Arc<Template>, BufrKitError> {
+        Ok(self.get_compiled(table_group, unexpanded_descriptors, max_depth)?.0)
+    }
+
+    /// Get the template of the descriptors and its program, expanding and compiling
+    /// it on first use. Templates that fail to expand or compile are not cached.
+    pub fn get_compiled(&self,
+                        table_group: &TableGroup,
+                        unexpanded_descriptors: &[Fxy],
+                        max_depth: usize) -> Result<(Arc<Template>, Arc<Program>), BufrKitError> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let key = (table_group.id().clone(), unexpanded_descriptors.to_vec());
+        if let Some(cached) = self.cache.read().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            cached.last_used.store(now, Ordering::Relaxed);
+            // The template may have been expanded under a larger limit
+            if cached.template.depth() > max_depth {
+                return Err(BufrKitError::LimitExceeded {
+                    limit: "expansion depth",
+                    value: cached.template.depth(),
+                    max: max_depth,
+                });
+            }
+            return Ok((cached.template.clone(), cached.program.clone()));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let template = Arc::new(Template::with_max_depth(table_group, unexpanded_descriptors, max_depth)?);
+        let program = Arc::new(Program::compile(&template)?);
+        let mut cache = self.cache.write().unwrap();
+        cache.insert(key, CachedTemplate {
+            template: template.clone(),
+            program: program.clone(),
+            last_used: AtomicU64::new(now),
+        });
+        while cache.len() > self.capacity {
+            let victim = cache.iter()
+                .min_by_key(|(_, cached)| cached.last_used.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone());
+            match victim {
+                Some(key) => {
+                    cache.remove(&key);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+        Ok((template, program))
+    }
+
+    /// Remove all templates of the table group
+    pub fn evict_table_group(&self, table_group_id: &TableGroupId) {
+        let mut cache = self.cache.write().unwrap();
+        let before = cache.len();
+        cache.retain(|(id, _), _| id != table_group_id);
+        self.evictions.fetch_add((before - cache.len()) as u64, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        let mut cache = self.cache.write().unwrap();
+        self.evictions.fetch_add(cache.len() as u64, Ordering::Relaxed);
+        cache.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            size: self.cache.read().unwrap().len(),
+        }
     }
 }
 
+/// Descriptors expanded into a tree. The nodes are stored in an arena with the
+/// root first, so a template is immutable once built and can be shared between threads.
 #[derive(Debug)]
 pub struct Template {
-    ids: Vec<ID>,
+    ids: Vec<Fxy>,
     table_group_id: TableGroupId,
-    root: Rc<Node>,
+    nodes: Vec<Node>,
+    depth: usize,
 }
 
 impl Template {
     pub fn new(table_group: &TableGroup,
-               unexpanded_descriptors: &[isize]) -> Result<Template, BufrKitError> {
-        let root = Rc::new(Node {
-            descriptor: Descriptor::Sequence(SequenceDescriptor { id: 0, name: "ROOT".to_owned() }),
-            parent: RefCell::new(Weak::new()),
-            children: RefCell::new(Vec::new()),
-        });
-        expand_members(table_group, &root, unexpanded_descriptors.into())?;
+               unexpanded_descriptors: &[Fxy]) -> Result<Template, BufrKitError> {
+        Template::with_max_depth(table_group, unexpanded_descriptors, DEFAULT_MAX_DEPTH)
+    }
+
+    /// Expand the descriptors, failing if sequences and replications nest deeper than `max_depth`
+    pub fn with_max_depth(table_group: &TableGroup,
+                          unexpanded_descriptors: &[Fxy],
+                          max_depth: usize) -> Result<Template, BufrKitError> {
+        let mut nodes = vec![Node {
+            descriptor: Descriptor::Sequence(SequenceDescriptor { id: Fxy::default(), name: "ROOT".to_owned() }),
+            parent: None,
+            children: Vec::new(),
+        }];
+        expand_members(table_group, &mut nodes, 0, unexpanded_descriptors, 1, max_depth)?;
+        // Parents come before their children in the arena
+        let mut levels = vec![0; nodes.len()];
+        for i in 1..nodes.len() {
+            levels[i] = nodes[i].parent.map_or(0, |parent| levels[parent]) + 1;
+        }
         Ok(Template {
             ids: unexpanded_descriptors.to_owned(),
             table_group_id: table_group.id().clone(),
-            root,
+            depth: levels.into_iter().max().unwrap_or(0),
+            nodes,
         })
     }
 
-    pub fn accept(&self, visitor: &mut dyn Visitor) {
-        for node in self.root.children.borrow().iter() {
-            node.accept(visitor)
-        }
+    pub fn ids(&self) -> &[Fxy] {
+        &self.ids
+    }
+
+    pub fn table_group_id(&self) -> &TableGroupId {
+        &self.table_group_id
+    }
+
+    /// Nesting depth of the deepest descriptor, where top level descriptors are at depth 1
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The root sequence, whose children are the unexpanded descriptors
+    pub fn root(&self) -> NodeRef<'_> {
+        NodeRef { template: self, index: 0 }
+    }
+
+    pub fn accept(&self, visitor: &mut dyn Visitor) -> Result<(), BufrKitError> {
+        self.root().children().accept(visitor)?;
+        Ok(())
     }
 }
 
+/// Walks an expanded template. Every method defaults to doing nothing and continuing,
+/// so visitors only implement what they need. Children of replications and sequences
+/// are passed in for visitors that want to walk them themselves, e.g. once per
+/// replication, in which case they should return `Flow::SkipChildren`.
 pub trait Visitor {
-    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor);
-    fn visit_replication_descriptor(&mut self, descriptor: &ReplicationDescriptor, children: Ref<Vec<Rc<Node>>>);
-    fn visit_operator_descriptor(&mut self, descriptor: &OperatorDescriptor);
-    fn visit_sequence_descriptor(&mut self, descriptor: &SequenceDescriptor, children: Ref<Vec<Rc<Node>>>);
-    fn visit_replication_factor(&mut self, descriptor: &ElementDescriptor);
-    fn exit_replication_descriptor(&mut self);
-    fn exit_sequence_descriptor(&mut self);
+    fn visit_element_descriptor(&mut self, _descriptor: &ElementDescriptor) -> VisitResult {
+        Ok(Flow::Continue)
+    }
+
+    fn visit_replication_descriptor(&mut self, _descriptor: &ReplicationDescriptor,
+                                    _children: Children<'_>) -> VisitResult {
+        Ok(Flow::Continue)
+    }
+
+    fn visit_operator_descriptor(&mut self, _descriptor: &OperatorDescriptor) -> VisitResult {
+        Ok(Flow::Continue)
+    }
+
+    fn visit_sequence_descriptor(&mut self, _descriptor: &SequenceDescriptor,
+                                 _children: Children<'_>) -> VisitResult {
+        Ok(Flow::Continue)
+    }
+
+    fn visit_replication_factor(&mut self, _descriptor: &ElementDescriptor) -> VisitResult {
+        Ok(Flow::Continue)
+    }
+
+    fn exit_replication_descriptor(&mut self, _descriptor: &ReplicationDescriptor) -> Result<(), BufrKitError> {
+        Ok(())
+    }
+
+    fn exit_sequence_descriptor(&mut self, _descriptor: &SequenceDescriptor) -> Result<(), BufrKitError> {
+        Ok(())
+    }
 }
 
 pub struct PrintVisitor {
     indent_level: usize
 }
 
+impl Default for PrintVisitor {
+    fn default() -> Self {
+        PrintVisitor::new()
+    }
+}
+
 impl PrintVisitor {
     pub fn new() -> Self {
         PrintVisitor {
@@ -114,55 +408,74 @@ impl PrintVisitor {
     }
 }
 
-impl Visitor for PrintVisitor {
-    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor) {
+impl PrintVisitor {
+    fn print(&self, descriptor: &dyn fmt::Display) -> VisitResult {
         println!("{:indent_level$}{}", "", descriptor, indent_level = self.indent_level);
+        Ok(Flow::Continue)
     }
+}
 
-    fn visit_replication_descriptor<'b>(&mut self, descriptor: &ReplicationDescriptor, children: Ref<'b, Vec<Rc<Node>>>) {
-        println!("{:indent_level$}{}", "", descriptor, indent_level = self.indent_level);
+impl Visitor for PrintVisitor {
+    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor) -> VisitResult {
+        self.print(descriptor)
+    }
+
+    fn visit_replication_descriptor(&mut self, descriptor: &ReplicationDescriptor,
+                                        children: Children<'_>) -> VisitResult {
+        self.print(descriptor)?;
         self.indent_level += 4;
+        Ok(Flow::Continue)
     }
 
-    fn visit_operator_descriptor(&mut self, descriptor: &OperatorDescriptor) {
-        println!("{:indent_level$}{}", "", descriptor, indent_level = self.indent_level);
+    fn visit_operator_descriptor(&mut self, descriptor: &OperatorDescriptor) -> VisitResult {
+        self.print(descriptor)
     }
 
-    fn visit_sequence_descriptor<'b>(&mut self, descriptor: &SequenceDescriptor, children: Ref<'b, Vec<Rc<Node>>>) {
-        println!("{:indent_level$}{}", "", descriptor, indent_level = self.indent_level);
+    fn visit_sequence_descriptor(&mut self, descriptor: &SequenceDescriptor,
+                                     children: Children<'_>) -> VisitResult {
+        self.print(descriptor)?;
         self.indent_level += 4;
+        Ok(Flow::Continue)
     }
 
-    fn visit_replication_factor(&mut self, descriptor: &ElementDescriptor) {
-        println!("{:indent_level$}{}", "", descriptor, indent_level = self.indent_level);
+    fn visit_replication_factor(&mut self, descriptor: &ElementDescriptor) -> VisitResult {
+        self.print(descriptor)
     }
 
-    fn exit_replication_descriptor(&mut self) {
+    fn exit_replication_descriptor(&mut self, descriptor: &ReplicationDescriptor) -> Result<(), BufrKitError> {
         self.indent_level -= 4;
+        Ok(())
     }
 
-    fn exit_sequence_descriptor(&mut self) {
+    fn exit_sequence_descriptor(&mut self, descriptor: &SequenceDescriptor) -> Result<(), BufrKitError> {
         self.indent_level -= 4;
+        Ok(())
     }
 }
 
 fn expand_members(table_group: &TableGroup,
-                  parent: &Rc<Node>,
-                  member_ids: Vec<ID>) -> Result<(), BufrKitError> {
+                  nodes: &mut Vec<Node>,
+                  parent: usize,
+                  member_ids: &[Fxy],
+                  depth: usize,
+                  max_depth: usize) -> Result<(), BufrKitError> {
     let member_id_supplier = &mut member_ids.iter().peekable();
     while member_id_supplier.peek().is_some() {
-        parent.children.borrow_mut().push(expand_one(
-            table_group, &parent, member_id_supplier,
-        )?);
+        let child = expand_one(table_group, nodes, parent, member_id_supplier, depth, max_depth)?;
+        nodes[parent].children.push(child);
     };
     Ok(())
 }
 
-pub fn expand_one(table_group: &TableGroup,
-                  parent: &Rc<Node>,
-                  id_supplier: &mut Peekable<Iter<ID>>) -> Result<Rc<Node>, BufrKitError> {
+/// Expand the next descriptor into the arena, returning its index
+fn expand_one(table_group: &TableGroup,
+              nodes: &mut Vec<Node>,
+              parent: usize,
+              id_supplier: &mut Peekable<Iter<Fxy>>,
+              depth: usize,
+              max_depth: usize) -> Result<usize, BufrKitError> {
     let id = *id_supplier.next()
-        .ok_or(BufrKitError { message: format!("insufficient IDs") })?;
+        .ok_or_else(|| BufrKitError::InvalidDescriptor("insufficient IDs".to_owned()))?;
 
     let (descriptor, member_ids) = match table_group.lookup(id)? {
         Entry::B(bentry) => {
@@ -195,18 +508,22 @@ pub fn expand_one(table_group: &TableGroup,
             for _ in 0..n_members {
                 member_ids.push(
                     *id_supplier.next()
-                        .ok_or(BufrKitError { message: format!("insufficient IDs") })?);
+                        .ok_or_else(|| BufrKitError::InvalidDescriptor("insufficient IDs".to_owned()))?);
+            }
+            if rentry.n_repeats() == 0 && member_ids[0].f() != 0 {
+                return Err(BufrKitError::InvalidDescriptor(
+                    format!("{}: expected an element descriptor as replication factor, got {}", id, member_ids[0])));
             }
             (Descriptor::Replication(ReplicationDescriptor { id }), member_ids)
         }
     };
-    let node = Rc::new(Node {
-        descriptor,
-        parent: RefCell::new(Rc::downgrade(parent)),
-        children: RefCell::new(vec![]),
-    });
-    if member_ids.len() > 0 {
-        expand_members(table_group, &node, member_ids)?;
-    }
-    Ok(node)
+    let index = nodes.len();
+    nodes.push(Node { descriptor, parent: Some(parent), children: Vec::new() });
+    if !member_ids.is_empty() {
+        if depth >= max_depth {
+            return Err(BufrKitError::LimitExceeded { limit: "expansion depth", value: depth + 1, max: max_depth });
+        }
+        expand_members(table_group, nodes, index, &member_ids, depth + 1, max_depth)?;
+    }
+    Ok(index)
 }
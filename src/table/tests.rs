@@ -1,11 +1,21 @@
 use super::*;
-use crate::table::table::{TableGroup, TableGroupId, TableGroupManager};
+use crate::table::table::{CacheStats, TableGroup, TableGroupId, TableGroupManager};
 use std::ptr;
 use std::ops::Deref;
-use crate::table::template::{expand_one, Node, Template, PrintVisitor};
-use std::cell::RefCell;
-use std::rc::{Weak, Rc};
+use crate::table::descriptor::Fxy;
+use crate::table::template::{Children, Flow, Template, TemplateCache, PrintVisitor, Visitor, VisitResult, DEFAULT_MAX_DEPTH};
+use crate::table::descriptor::{ElementDescriptor, SequenceDescriptor};
+use crate::table::check::{check_table_group, Problem};
+use crate::table::search::{NameMatcher, search, used_by};
+use crate::table::diff::{diff_table_groups, DiffKind, EntryDiff, FieldChange};
 use std::borrow::Borrow;
+use std::fs;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use std::thread;
+use crate::BufrKitError;
 
 #[test]
 fn test_table_group_manager() {
@@ -23,6 +33,46 @@ fn test_table_group_manager() {
     ptr::eq(t1.deref(), t2.deref());
 }
 
+#[test]
+fn test_table_group_manager_single_flight() {
+    let tgm = Arc::new(TableGroupManager::new());
+    let tg_id = table_group_id(25);
+    let handles: Vec<_> = (0..4).map(|_| {
+        let tgm = tgm.clone();
+        let tg_id = tg_id.clone();
+        thread::spawn(move || tgm.get_table_group(&tg_id).unwrap())
+    }).collect();
+    let table_groups: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert!(table_groups.iter().all(|tg| Arc::ptr_eq(tg, &table_groups[0])));
+
+    let stats = tgm.stats();
+    assert_eq!(1, stats.misses);
+    assert_eq!(3, stats.hits);
+    assert_eq!(1, stats.size);
+}
+
+#[test]
+fn test_table_group_manager_capacity() {
+    let tgm = TableGroupManager::with_capacity(2);
+    tgm.preload(&[table_group_id(24), table_group_id(25)]).unwrap();
+    tgm.get_table_group(&table_group_id(24)).unwrap();
+    tgm.get_table_group(&table_group_id(26)).unwrap();
+
+    // 25 is the least recently used one
+    assert_eq!(2, tgm.size());
+    assert!(!tgm.evict(&table_group_id(25)));
+    assert!(tgm.evict(&table_group_id(24)));
+    assert_eq!(CacheStats { hits: 1, misses: 3, evictions: 2, size: 1 }, tgm.stats());
+}
+
+#[test]
+fn test_table_group_manager_load_error() {
+    let tgm = TableGroupManager::new();
+    assert!(tgm.get_table_group(&table_group_id(999)).is_err());
+    assert_eq!(0, tgm.size());
+    assert_eq!(1, tgm.stats().misses);
+}
+
 #[test]
 fn test_load_table_group() {
     let table_group = create_table_group();
@@ -34,26 +84,47 @@ fn test_load_table_group() {
 fn test_lookup_descriptor() {
     let table_group = create_table_group();
 
-    table_group.lookup(1001).unwrap();
-    table_group.lookup(101000).unwrap();
-    table_group.lookup(201011).unwrap();
-    table_group.lookup(225255).unwrap();
-    table_group.lookup(300002).unwrap();
+    table_group.lookup(fxy(1001)).unwrap();
+    table_group.lookup(fxy(101000)).unwrap();
+    table_group.lookup(fxy(201011)).unwrap();
+    table_group.lookup(fxy(225255)).unwrap();
+    table_group.lookup(fxy(300002)).unwrap();
 }
 
 #[test]
 #[should_panic]
 fn test_lookup_bad_descriptor() {
     let table_group = create_table_group();
-    table_group.lookup(987654).unwrap();
+    table_group.lookup(fxy(363255)).unwrap();
+}
+
+#[test]
+fn test_fxy() {
+    let id = fxy(12101);
+    assert_eq!((0, 12, 101, 12), (id.f(), id.x(), id.y(), id.fx()));
+    assert_eq!("012101", id.to_string());
+    assert_eq!(12101u32, u32::from(id));
+    for s in &["012101", "12101", "0 12 101", "0-12-101", " 0  12 101 "] {
+        assert_eq!(id, s.parse::<Fxy>().unwrap());
+    }
+    for s in &["412101", "0-64-001", "0 12 256", "0-12", "x12101", "1234567", "-12101"] {
+        assert!(s.parse::<Fxy>().is_err(), "{}", s);
+    }
+    assert!(Fxy::try_from(987654).is_err());
+    assert!(Fxy::new(3, 63, 255).is_ok());
+
+    assert_eq!("\"012101\"", serde_json::to_string(&id).unwrap());
+    assert_eq!(id, serde_json::from_str::<Fxy>("\"0 12 101\"").unwrap());
+    assert_eq!(id, serde_json::from_str::<Fxy>("12101").unwrap());
+    assert!(serde_json::from_str::<Fxy>("-1").is_err());
 }
 
 #[test]
 fn test_lookup_cnf() {
     let table_group = create_table_group();
 
-    assert_eq!("REGION V", table_group.lookup_cnf(1003, 5).unwrap());
-    assert_eq!("REGION V", table_group.lookup_cnf(1003, 5).unwrap());
+    assert_eq!("REGION V", table_group.lookup_cnf(fxy(1003), 5).unwrap());
+    assert_eq!("REGION V", table_group.lookup_cnf(fxy(1003), 5).unwrap());
 }
 
 #[test]
@@ -62,22 +133,22 @@ fn test_lookup_meta() {
 
     assert_eq!(
         "Identification: Identifies origin and type of data",
-        table_group.lookup_meta(1001).unwrap(),
+        table_group.lookup_meta(fxy(1001)).unwrap(),
     );
 
     assert_eq!(
         "Change data width",
-        table_group.lookup_meta(201011).unwrap(),
+        table_group.lookup_meta(fxy(201011)).unwrap(),
     );
 
     assert_eq!(
         "Difference statistical values follow",
-        table_group.lookup_meta(225000).unwrap(),
+        table_group.lookup_meta(fxy(225000)).unwrap(),
     );
 
     assert_eq!(
         "Location and identification sequences",
-        table_group.lookup_meta(301059).unwrap(),
+        table_group.lookup_meta(fxy(301059)).unwrap(),
     );
 }
 
@@ -100,18 +171,333 @@ fn test_data_category_of() {
 fn test_template() {
     let table_group = create_table_group();
     let template = Template::new(
-        &table_group, &[302059, 1001]).unwrap();
+        &table_group, &[fxy(302059), fxy(1001)]).unwrap();
     println!("Template is {:?}", template)
 }
 
+#[test]
+fn test_template_bad_replication_factor() {
+    let table_group = create_table_group();
+    let err = Template::new(&table_group, &[fxy(101000), fxy(301001), fxy(1001)]).unwrap_err();
+    assert!(matches!(err, BufrKitError::InvalidDescriptor(_)));
+    assert!(Template::new(&table_group, &[fxy(101000)]).is_err());
+}
+
+#[test]
+fn test_template_max_depth() {
+    let table_group = create_table_group();
+    assert!(Template::with_max_depth(&table_group, &[fxy(1001)], 1).is_ok());
+    assert!(Template::with_max_depth(&table_group, &[fxy(301001)], 2).is_ok());
+    let err = Template::with_max_depth(&table_group, &[fxy(301001)], 1).unwrap_err();
+    assert!(matches!(err, BufrKitError::LimitExceeded { value: 2, max: 1, .. }));
+}
+
+#[test]
+fn test_template_cache() {
+    let table_group = create_table_group();
+    let cache = TemplateCache::with_capacity(2);
+    let t1 = cache.get_template(&table_group, &[fxy(301001)], DEFAULT_MAX_DEPTH).unwrap();
+    let t2 = cache.get_template(&table_group, &[fxy(301001)], DEFAULT_MAX_DEPTH).unwrap();
+    assert!(Arc::ptr_eq(&t1, &t2));
+    assert_eq!(2, t1.depth());
+
+    // A cached template is still checked against a smaller limit
+    let err = cache.get_template(&table_group, &[fxy(301001)], 1).unwrap_err();
+    assert!(matches!(err, BufrKitError::LimitExceeded { value: 2, max: 1, .. }));
+    // and reports its actual depth
+    let other = TemplateCache::with_capacity(2);
+    assert_eq!(3, other.get_template(&table_group, &[fxy(101002), fxy(301001)], DEFAULT_MAX_DEPTH).unwrap().depth());
+    let err = other.get_template(&table_group, &[fxy(101002), fxy(301001)], 1).unwrap_err();
+    assert!(matches!(err, BufrKitError::LimitExceeded { value: 3, max: 1, .. }));
+
+    // Templates that fail to expand are not cached
+    assert!(cache.get_template(&table_group, &[fxy(101000)], DEFAULT_MAX_DEPTH).is_err());
+    assert_eq!(CacheStats { hits: 2, misses: 2, evictions: 0, size: 1 }, cache.stats());
+
+    cache.get_template(&table_group, &[fxy(1001)], DEFAULT_MAX_DEPTH).unwrap();
+    cache.get_template(&table_group, &[fxy(1002)], DEFAULT_MAX_DEPTH).unwrap();
+    let stats = cache.stats();
+    assert_eq!((1, 2), (stats.evictions, stats.size));
+    let t3 = cache.get_template(&table_group, &[fxy(301001)], DEFAULT_MAX_DEPTH).unwrap();
+    assert!(!Arc::ptr_eq(&t1, &t3));
+
+    // The program is compiled along with the template
+    let (t4, p1) = cache.get_compiled(&table_group, &[fxy(301001)], DEFAULT_MAX_DEPTH).unwrap();
+    let (_, p2) = cache.get_compiled(&table_group, &[fxy(301001)], DEFAULT_MAX_DEPTH).unwrap();
+    assert!(Arc::ptr_eq(&t3, &t4));
+    assert!(Arc::ptr_eq(&p1, &p2));
+
+    cache.clear();
+    assert_eq!(0, cache.stats().size);
+}
+
+#[test]
+fn test_template_navigation() {
+    let table_group = create_table_group();
+    let template = Template::new(&table_group, &[fxy(301001), fxy(1003)]).unwrap();
+    let root = template.root();
+    assert!(root.parent().is_none());
+    assert_eq!(root.children().len(), 2);
+
+    let sequence = root.children().get(0).unwrap();
+    assert_eq!(sequence.descriptor().id(), fxy(301001));
+    assert_eq!(sequence.parent().unwrap().descriptor().id(), root.descriptor().id());
+    let element = sequence.children().get(1).unwrap();
+    assert_eq!(element.descriptor().id(), fxy(1002));
+    assert_eq!(element.parent().unwrap().descriptor().id(), fxy(301001));
+    assert_eq!(sequence.children().skip(1).len(), 1);
+    assert!(root.children().get(2).is_none());
+}
+
+#[test]
+fn test_template_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Template>();
+
+    let table_group = create_table_group();
+    let template = Arc::new(Template::new(&table_group, &[fxy(309052)]).unwrap());
+    let handles: Vec<_> = (0..4).map(|_| {
+        let template = Arc::clone(&template);
+        thread::spawn(move || {
+            let mut visitor = CountingVisitor::default();
+            template.accept(&mut visitor).unwrap();
+            visitor.n_elements
+        })
+    }).collect();
+    let counts: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert!(counts[0] > 0);
+    assert!(counts.iter().all(|&n| n == counts[0]));
+}
+
 #[test]
 fn test_print_visitor() {
     let table_group = create_table_group();
     let template = Template::new(
-        &table_group, &[309052]).unwrap();
+        &table_group, &[fxy(309052)]).unwrap();
 
     let mut print_visitor = PrintVisitor::new();
-    template.accept(&mut print_visitor);
+    template.accept(&mut print_visitor).unwrap();
+}
+
+#[derive(Default)]
+struct CountingVisitor {
+    n_elements: usize,
+    n_exits: usize,
+    skip_sequences: bool,
+    stop_after: Option<usize>,
+}
+
+impl Visitor for CountingVisitor {
+    fn visit_element_descriptor(&mut self, descriptor: &ElementDescriptor) -> VisitResult {
+        self.n_elements += 1;
+        if Some(self.n_elements) == self.stop_after { Ok(Flow::Stop) } else { Ok(Flow::Continue) }
+    }
+
+    fn visit_sequence_descriptor(&mut self, descriptor: &SequenceDescriptor,
+                                 children: Children<'_>) -> VisitResult {
+        if self.skip_sequences { Ok(Flow::SkipChildren) } else { Ok(Flow::Continue) }
+    }
+
+    fn exit_sequence_descriptor(&mut self, descriptor: &SequenceDescriptor) -> Result<(), BufrKitError> {
+        self.n_exits += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_visitor_flow() {
+    let table_group = create_table_group();
+    let template = Template::new(&table_group, &[fxy(301001), fxy(1001), fxy(301001)]).unwrap();
+
+    let mut visitor = CountingVisitor::default();
+    template.accept(&mut visitor).unwrap();
+    assert_eq!(5, visitor.n_elements);
+    assert_eq!(2, visitor.n_exits);
+
+    let mut visitor = CountingVisitor { skip_sequences: true, ..CountingVisitor::default() };
+    template.accept(&mut visitor).unwrap();
+    assert_eq!(1, visitor.n_elements);
+    assert_eq!(2, visitor.n_exits);
+
+    let mut visitor = CountingVisitor { stop_after: Some(3), ..CountingVisitor::default() };
+    template.accept(&mut visitor).unwrap();
+    assert_eq!(3, visitor.n_elements);
+    assert_eq!(1, visitor.n_exits);
+}
+
+#[test]
+fn test_table_group_id_from_path() {
+    let tg_id = TableGroupId::from_path("_definitions/tables/0/0_0/25/").unwrap();
+    assert_eq!(create_table_group().id(), &tg_id);
+    assert!(TableGroupId::from_path("25").is_err());
+    assert!(TableGroupId::from_path("_definitions/tables/0/0/25").is_err());
+}
+
+#[test]
+fn test_check_table_group() {
+    let problems = check_table_group(&create_table_group());
+    assert!(problems.contains(&Problem { id: fxy(1031), message: "code table entries not found".to_owned() }));
+    assert!(problems.iter().all(|p| p.id.f() == 0));
+}
+
+#[test]
+fn test_check_broken_table_group() {
+    let tables = TempTableGroup::new("check", r#"{
+        "001001": [ "WMO BLOCK NUMBER", "Numeric", 0, 0, 7, "Numeric", 0, 2 ],
+        "001003": [ "WMO REGION NUMBER", "CODE TABLE", 0, 0, 3, "Code table", 0, 1 ],
+        "001007": [ "SATELLITE IDENTIFIER", "CODE TABLE", 0, 0, 10, "Code table", 0, 4 ],
+        "001015": [ "STATION OR SITE NAME", "CCITT IA5", 0, 0, 140, "Character", 0, 20 ],
+        "002002": [ "TYPE OF INSTRUMENTATION", "FLAG TABLE", 0, 0, 4, "Flag table", 0, 2 ],
+        "012001": [ "TEMPERATURE", "K", 1, 4294967000, 12, "C", 1, 3 ],
+        "012002": [ "TEMPERATURE DIFFERENCE", "K", 1, -2147483648, 12, "C", 1, 3 ],
+        "012003": [ "DEW-POINT TEMPERATURE", "K", 1, -2147483647, 31, "C", 1, 3 ],
+        "012004": [ "DRY-BULB TEMPERATURE AT 2M", "K", 1, 2000000000, 32, "C", 1, 3 ],
+        "012005": [ "WET-BULB TEMPERATURE AT 2M", "K", 1, -1, 32, "C", 1, 3 ],
+        "012006": [ "DEW-POINT TEMPERATURE AT 2M", "K", 1, 0, 32, "C", 1, 3 ],
+        "031001": [ "DELAYED DESCRIPTOR REPLICATION FACTOR", "Numeric", 0, 0, 8, "Numeric", 0, 3 ]
+    }"#, r#"{
+        "301001": [ "", [ "001001", "001002" ] ],
+        "301002": [ "", [ "001001", "301003" ] ],
+        "301003": [ "", [ "101000", "031001", "301002" ] ]
+    }"#, r#"{
+        "001003": [ [ 1, "REGION I" ], [ 8, "TOO LARGE" ] ],
+        "002002": [ [ 2, "KNOTS" ], [ 5, "TOO LARGE" ] ],
+        "063255": [ [ 1, "UNKNOWN" ] ]
+    }"#);
+
+    let table_group = tables.load();
+    let problems: Vec<String> = check_table_group(&table_group).iter()
+        .map(|p| p.to_string()).collect();
+
+    assert_eq!(vec![
+        "001003: code value 8 does not fit in 3 bits",
+        "001007: code table entries not found",
+        "001015: character data width 140 is not a multiple of 8",
+        "002002: flag bit 5 is outside of the 4 bits data width",
+        "012001: reference value 4294967000 cannot be represented in 32 bits",
+        "012002: reference value -2147483648 cannot be represented in 32 bits",
+        "012004: values of 32 bits with reference value 2000000000 exceed the 32 bits range",
+        "012005: values of 32 bits with reference value -1 exceed the 32 bits range",
+        "063255: code and flag entries for an unknown element",
+        "301001: member 001002 cannot be resolved: 001002 not found",
+        "301002: recursive sequence: 301002 -> 301003 -> 301002",
+    ], problems);
+}
+
+#[test]
+fn test_diff_table_groups() {
+    let table_group = create_table_group();
+    assert!(diff_table_groups(&table_group, &table_group).is_empty());
+
+    let old = TableGroup::load(&table_group_id(32)).unwrap();
+    let new = TableGroup::load(&table_group_id(33)).unwrap();
+    let diff = diff_table_groups(&old, &new);
+    assert_eq!(EntryDiff {
+        id: fxy(2092),
+        kind: DiffKind::Added,
+        name: "OZONE PROFILE COMPUTATION METHOD".to_owned(),
+        changes: vec![],
+    }, diff.table_b[0]);
+    assert!(diff.table_d.iter().any(|d| d.id == fxy(311012) && d.kind == DiffKind::Added));
+
+    let satellites = diff.code_and_flag.iter().find(|d| d.id == fxy(1007)).unwrap();
+    assert_eq!(DiffKind::Changed, satellites.kind);
+    assert!(satellites.changes.contains(&FieldChange {
+        field: "810".to_owned(),
+        old: Some("COMS-1".to_owned()),
+        new: Some("COMS".to_owned()),
+    }));
+    assert!(format!("{}", diff).contains("~ 001007 SATELLITE IDENTIFIER\n      66: (none) -> SENTINEL-6A"));
+
+    let reverse = diff_table_groups(&new, &old);
+    assert_eq!(DiffKind::Removed, reverse.table_b[0].kind);
+}
+
+#[test]
+fn test_diff_sequence_members() {
+    let old = create_table_group();
+    let dentry = old.table_d().get(fxy(301001)).unwrap();
+    assert_eq!(vec![fxy(1001), fxy(1002)], dentry.members);
+
+    let tables = TempTableGroup::new("diff", "{}", r#"{
+        "301001": [ "(WMO block and station numbers)", [ "001003", "001002", "001004" ] ]
+    }"#, "{}");
+    let new = tables.load();
+
+    let diff = diff_table_groups(&old, &new);
+    let block_and_station = diff.table_d.iter().find(|d| d.id == fxy(301001)).unwrap();
+    let change = |field: &str, old: Option<&str>, new: Option<&str>| FieldChange {
+        field: field.to_owned(),
+        old: old.map(|s| s.to_owned()),
+        new: new.map(|s| s.to_owned()),
+    };
+    assert_eq!(vec![
+        change("member 1", Some("001001"), Some("001003")),
+        change("member 3", None, Some("001004")),
+    ], block_and_station.changes);
+    assert!(format!("{}", block_and_station).contains("member 3: (none) -> 001004"));
+
+    let reverse = diff_table_groups(&new, &old);
+    let block_and_station = reverse.table_d.iter().find(|d| d.id == fxy(301001)).unwrap();
+    assert_eq!(vec![
+        change("member 1", Some("001003"), Some("001001")),
+        change("member 3", Some("001004"), None),
+    ], block_and_station.changes);
+}
+
+#[test]
+fn test_search() {
+    let table_group = create_table_group();
+    let found = search(&table_group, &NameMatcher::keywords("dew point"));
+    assert_eq!((fxy(12003), "DEWPOINT TEMPERATURE"), found[0]);
+    assert!(found.iter().any(|(id, _)| *id == fxy(309007)));
+
+    let found = search(&table_group, &NameMatcher::regex("^wmo (block|station) number$").unwrap());
+    assert_eq!(vec![(fxy(1001), "WMO BLOCK NUMBER"), (fxy(1002), "WMO STATION NUMBER")], found);
+    assert!(NameMatcher::regex("(").is_err());
+}
+
+#[test]
+fn test_used_by() {
+    let table_group = create_table_group();
+    let found = used_by(&table_group, fxy(12101));
+    assert!(found.contains(&(fxy(302032), true)));
+    assert!(found.contains(&(fxy(302035), false)));
+    assert!(used_by(&table_group, fxy(63255)).is_empty());
+}
+
+/// A table group written to a temporary directory, next to a copy of the common
+/// meta tables. The directory is removed on drop, including when a test fails.
+struct TempTableGroup {
+    base_dir: PathBuf,
+    group_dir: PathBuf,
+}
+
+impl TempTableGroup {
+    fn new(name: &str, table_b: &str, table_d: &str, code_and_flag: &str) -> Self {
+        let base_dir = env::temp_dir().join(format!("rustbufrkit-{}-{}", name, std::process::id()));
+        let tables = TempTableGroup { group_dir: base_dir.join("0").join("0_0").join("1"), base_dir };
+        fs::create_dir_all(&tables.group_dir).unwrap();
+        fs::create_dir_all(tables.base_dir.join("common")).unwrap();
+        for name in &["MetaA", "MetaB", "MetaC", "MetaD"] {
+            let filename = format!("{}.json", name);
+            fs::copy(format!("_definitions/tables/common/{}", filename), tables.base_dir.join("common").join(&filename)).unwrap();
+        }
+        fs::write(tables.group_dir.join("TableB.json"), table_b).unwrap();
+        fs::write(tables.group_dir.join("TableD.json"), table_d).unwrap();
+        fs::write(tables.group_dir.join("code_and_flag.json"), code_and_flag).unwrap();
+        tables
+    }
+
+    fn load(&self) -> TableGroup {
+        TableGroup::load(&TableGroupId::from_path(self.group_dir.to_str().unwrap()).unwrap()).unwrap()
+    }
+}
+
+impl Drop for TempTableGroup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.base_dir);
+    }
 }
 
 fn create_table_group() -> TableGroup {
@@ -122,4 +508,18 @@ fn create_table_group() -> TableGroup {
         sub_centre_number: 0,
         version_number: 25,
     }).unwrap()
-}
\ No newline at end of file
+}
+
+fn table_group_id(version_number: isize) -> TableGroupId {
+    TableGroupId {
+        base_dir: String::from("_definitions/tables"),
+        master_table_number: 0,
+        centre_number: 0,
+        sub_centre_number: 0,
+        version_number,
+    }
+}
+
+fn fxy(id: u32) -> Fxy {
+    Fxy::try_from(id).unwrap()
+}
@@ -1,8 +1,988 @@
-use std::io::BufReader;
-use crate::decoder::find_string;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::error::Error;
+use std::fs;
+use std::convert::TryFrom;
+use crate::table::descriptor::Fxy;
+use crate::bufr::{BufrMessage, BufrSection, DataValue, Field, FieldSimple, Section1Header, SimpleData, Timestamp, Warning,
+                  WarningKind};
+use crate::decoder::{decode_binary, decode_messages, decode_messages_parallel, decode_slice, decode_stream, find_string, scan,
+                     table_group_id, DecodeLimits, DecodeOptions};
+use crate::input::{expand_inputs, Compression, Input, Rewind};
+use crate::index::{template_hash, Index, IndexQuery, SourceInfo};
+use crate::table::table::{TableGroup, TableGroupId, TableGroupManager};
+use crate::table::template::{Template, DEFAULT_MAX_DEPTH};
+use crate::payload::{decode_with_program, decode_with_visitor, encode_with_program, BitWriter, PayloadReader};
+use crate::payload::program::{Instruction, Operand, Program};
+use bitreader::BitReader;
+use std::sync::Arc;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use crate::{BufrKitError, Position};
 
 #[test]
 fn test_find_string() {
     assert_eq!(find_string("BUFR", &mut "xxBUFRyyy7777zzz".as_bytes()).unwrap(), 2);
     assert_eq!(find_string("7777", &mut "xxBUFRyyy7777zzz".as_bytes()).unwrap(), 9);
-}
\ No newline at end of file
+    assert_eq!(find_string("BUFR", &mut "xBBUFRy".as_bytes()).unwrap(), 2);
+    assert!(matches!(find_string("BUFR", &mut "xxBUF".as_bytes()), Err(BufrKitError::UnexpectedEof)));
+
+    // Split between buffers, and consumed up to the end of the string
+    for capacity in 1..8 {
+        let mut r = BufReader::with_capacity(capacity, "xxxBBUFRyz".as_bytes());
+        assert_eq!(find_string("BUFR", &mut r).unwrap(), 4, "capacity {}", capacity);
+        let mut rest = String::new();
+        r.read_to_string(&mut rest).unwrap();
+        assert_eq!("yz", rest);
+    }
+}
+
+#[test]
+fn test_input() {
+    let input = unsafe { Input::map("tests/data/contrived.bufr") }.unwrap();
+    assert!(input.is_mapped());
+    let bytes = input.bytes().unwrap();
+    assert_eq!(&fs::read("tests/data/contrived.bufr").unwrap()[..], bytes);
+    let table_group_manager = TableGroupManager::new();
+    let bufr_message = decode_slice(&table_group_manager, bytes, &DecodeOptions::default()).unwrap();
+    assert_eq!(Some(94), bufr_message.try_length());
+    assert!(matches!(decode_slice(&table_group_manager, &bytes[1..], &DecodeOptions::default()),
+                     Err(BufrKitError::UnexpectedEof)));
+
+    let path = std::env::temp_dir().join(format!("rustbufrkit-empty-{}.bufr", std::process::id()));
+    fs::write(&path, b"").unwrap();
+    let input = unsafe { Input::map(&path) }.unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(!input.is_mapped());
+    assert!(input.bytes().unwrap().is_empty());
+
+    let input = Input::open("tests/data/contrived.bufr").unwrap();
+    assert!(!input.is_mapped());
+    assert_eq!(&fs::read("tests/data/contrived.bufr").unwrap()[..], input.bytes().unwrap());
+}
+
+#[test]
+fn test_compressed_input() {
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let table_group_manager = TableGroupManager::new();
+    let decompress = |input: Input| match input {
+        Input::Decompressed(mut r) => {
+            let mut bytes = Vec::new();
+            r.read_to_end(&mut bytes).map(|_| bytes)
+        }
+        _ => panic!("input is not streamed"),
+    };
+    for (extension, compression) in &[("gz", Compression::Gzip), ("bz2", Compression::Bzip2), ("zst", Compression::Zstd)] {
+        let path = format!("tests/data/contrived.bufr.{}", extension);
+        let compressed = fs::read(&path).unwrap();
+        assert_eq!(Some(*compression), Compression::detect(&compressed));
+
+        let input = unsafe { Input::map(&path) }.unwrap();
+        assert!(!input.is_mapped());
+        assert!(input.bytes().is_none());
+        assert_eq!(bytes, decompress(input).unwrap());
+        let bufr_message = decode_binary(&table_group_manager, &mut compressed.as_slice()).unwrap();
+        assert_eq!(Some(94), bufr_message.try_length());
+
+        // Concatenated compressed files decompress to the concatenated messages
+        let twice = [&compressed[..], &compressed[..]].concat();
+        let input = Input::read(Cursor::new(twice)).unwrap();
+        assert_eq!([&bytes[..], &bytes[..]].concat(), decompress(input).unwrap());
+
+        let input = Input::read(Cursor::new(compressed[..compressed.len() / 2].to_vec())).unwrap();
+        assert!(decompress(input).is_err());
+
+        let source = SourceInfo::of(&path).unwrap();
+        assert_eq!(Some(*compression), source.compression);
+        assert_eq!(compressed.len() as u64, source.length);
+    }
+    assert_eq!(None, Compression::detect(&bytes));
+    assert_eq!(&bytes[..], Input::read(Cursor::new(bytes.clone())).unwrap().bytes().unwrap());
+    assert_eq!(None, SourceInfo::of("tests/data/contrived.bufr").unwrap().compression);
+}
+
+#[test]
+fn test_rewind() {
+    let bytes: Vec<u8> = (0..100).collect();
+    let mut r = Rewind::new(BufReader::with_capacity(7, bytes.as_slice()), 20);
+    let mut b = [0u8; 10];
+    r.read_exact(&mut b).unwrap();
+    assert_eq!(&bytes[..10], &b);
+    assert_eq!(50, r.seek(SeekFrom::Start(50)).unwrap());
+    r.read_exact(&mut b).unwrap();
+    assert_eq!(&bytes[50..60], &b);
+    // Back over the last 20 bytes read, but not further
+    assert_eq!(40, r.seek(SeekFrom::Current(-20)).unwrap());
+    r.read_exact(&mut b).unwrap();
+    assert_eq!(&bytes[40..50], &b);
+    assert!(r.seek(SeekFrom::Start(10)).is_err());
+    // Seeking past the end stops at the end
+    assert_eq!(100, r.seek(SeekFrom::Start(200)).unwrap());
+    assert_eq!(0, r.read(&mut b).unwrap());
+}
+
+#[test]
+fn test_expand_inputs() {
+    let dir = std::env::temp_dir().join(format!("rustbufrkit-inputs-{}", std::process::id()));
+    fs::create_dir_all(dir.join("b")).unwrap();
+    for name in &["a.bufr", "a.bufr.idx", "b/c.bufr", "b/d.txt"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let name = |path: &str| dir.join(path).to_str().unwrap().to_owned();
+    let paths = expand_inputs(&[name(""), name("*.bufr"), name("b/*.none"), name("missing.bufr"), "-".to_owned()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let (paths, errors): (Vec<_>, Vec<_>) = paths.into_iter().partition(|path| path.is_ok());
+    let paths: Vec<PathBuf> = paths.into_iter().map(|path| path.unwrap()).collect();
+    assert_eq!(vec![dir.join("a.bufr"), dir.join("b/c.bufr"), dir.join("b/d.txt"), dir.join("a.bufr"),
+                    dir.join("missing.bufr"), PathBuf::from("-")], paths);
+    assert_eq!(1, errors.len());
+    assert!(errors[0].as_ref().unwrap_err().to_string().ends_with("no files match"));
+}
+
+#[test]
+fn test_try_accessors() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    assert_eq!(Some(4), bufr_message.try_edition());
+    assert_eq!(Some(94), bufr_message.try_length());
+
+    let section_0 = bufr_message.section(0).unwrap();
+    assert_eq!(Some("BUFR"), section_0.field_by_name("start_signature").unwrap().try_get_bytes());
+    assert_eq!(None, section_0.field_by_name("start_signature").unwrap().try_get_u32());
+    assert_eq!(None, section_0.field_by_name("edition").unwrap().try_get_unexpanded_descriptors());
+    assert!(section_0.field(3).is_none());
+
+    let section_3 = bufr_message.section(3).unwrap();
+    assert_eq!(Some(25), section_3.try_length());
+    let field = section_3.field_by_name("unexpanded_descriptors").unwrap();
+    assert_eq!(9, field.try_get_unexpanded_descriptors().unwrap().len());
+    assert!(field.try_get_simple_data().is_none());
+    assert!(bufr_message.section(6).is_none());
+}
+
+#[test]
+fn test_section1_header() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let header = bufr_message.header();
+    assert_eq!(4, header.edition);
+    assert_eq!(0, header.master_table_number);
+    assert_eq!(1, header.centre);
+    assert_eq!(0, header.subcentre);
+    assert_eq!(2, header.data_category);
+    assert_eq!(Some(4), header.data_i18n_subcategory);
+    assert_eq!(18, header.master_table_version);
+    assert!(!header.has_section2);
+    assert_eq!("2016-02-18T23:00:00", header.timestamp.to_string());
+}
+
+#[test]
+fn test_section1_header_edition_3() {
+    let field = |name: &str, v: u32| Field::SIMPLE(FieldSimple::new(name, SimpleData::U32(v)));
+    let section = BufrSection::new(1, vec!(
+        field("section_length", 18),
+        field("master_table_number", 0),
+        field("originating_subcentre", 3),
+        field("originating_centre", 98),
+        field("update_sequence_number", 0),
+        Field::SIMPLE(FieldSimple::new("is_section2_presents", SimpleData::BOOL(true))),
+        Field::SIMPLE(FieldSimple::new("flag_bits", SimpleData::FLAG(0, 7))),
+        field("data_category", 0),
+        field("data_local_subcategory", 2),
+        field("master_table_version", 13),
+        field("local_table_version", 0),
+        field("year", 99),
+        field("month", 12),
+        field("day", 31),
+        field("hour", 12),
+        field("minute", 30),
+    ));
+    let header = Section1Header::from_section(3, &section).unwrap();
+    assert_eq!(98, header.centre);
+    assert_eq!(3, header.subcentre);
+    assert!(header.has_section2);
+    assert_eq!(None, header.data_i18n_subcategory);
+    assert_eq!(Timestamp { year: 1999, month: 12, day: 31, hour: 12, minute: 30, second: 0 }, header.timestamp);
+
+    let section = BufrSection::new(1, vec!(field("year", 16)));
+    assert!(matches!(Section1Header::from_section(3, &section),
+                     Err(BufrKitError::InvalidSection { section: 1, .. })));
+}
+
+/// The contrived message laid out as edition 3, with sections padded to even lengths
+fn contrived_edition_3() -> Vec<u8> {
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let section_3 = &bytes[30..55];
+    let section_4 = &bytes[55..90];
+    let mut message = b"BUFR\0\0\0\x03".to_vec();
+    // section 1, with sub-centre before centre, year of century and a reserved byte
+    message.extend(&[0, 0, 18, 0, 0, 1, 0, 0, 2, 0, 18, 0, 16, 2, 18, 23, 0, 0]);
+    message.extend(&[0, 0, 26]);
+    message.extend(&section_3[3..]);
+    message.push(0);
+    message.extend(&[0, 0, 36]);
+    message.extend(&section_4[3..]);
+    message.push(0);
+    message.extend(b"7777");
+    let n = message.len() as u32;
+    message[4..7].copy_from_slice(&n.to_be_bytes()[1..]);
+    message
+}
+
+#[test]
+fn test_decode_edition_3() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let expected = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let bytes = contrived_edition_3();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let header = bufr_message.header();
+    assert_eq!(3, header.edition);
+    assert_eq!(1, header.centre);
+    assert_eq!(None, header.data_i18n_subcategory);
+    assert_eq!(18, header.master_table_version);
+    assert_eq!("2016-02-18T23:00:00", header.timestamp.to_string());
+    assert_eq!(expected.warnings().len(), bufr_message.warnings().len());
+    assert_eq!(format!("{:?}", expected.section(4).unwrap().field_by_name("template_data")),
+               format!("{:?}", bufr_message.section(4).unwrap().field_by_name("template_data")));
+
+    let summaries: Vec<_> = scan(Cursor::new(&bytes), &DecodeOptions::default()).collect();
+    assert_eq!(3, summaries[0].as_ref().unwrap().header.edition);
+}
+
+#[test]
+fn test_scan_edition_3_section_2() {
+    let table_group_manager = TableGroupManager::new();
+    let options = DecodeOptions::default();
+    let summary_of = |bytes: &[u8]| scan(Cursor::new(bytes), &options).next().unwrap().unwrap();
+
+    // A data sub-category with the bit that flags section 2 in edition 4
+    let mut bytes = contrived_edition_3();
+    bytes[17] = 200;
+    let summary = summary_of(&bytes);
+    assert!(!summary.header.has_section2);
+    assert_eq!(200, summary.header.data_local_subcategory);
+
+    let mut bytes = contrived_edition_3();
+    bytes[15] = 0x80;
+    bytes.splice(26..26, [0, 0, 8, 0, 1, 2, 3, 4]);
+    let n = bytes.len() as u32;
+    bytes[4..7].copy_from_slice(&n.to_be_bytes()[1..]);
+    let summary = summary_of(&bytes);
+    assert!(summary.header.has_section2);
+    assert_eq!(n as usize, summary.length);
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    assert_eq!(bufr_message.header(), &summary.header);
+}
+
+#[test]
+fn test_decode_error_short_message_length() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    bytes[4..7].copy_from_slice(&[0, 0, 3]);
+    let err = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap_err();
+    assert_eq!(Some(0), err.position().unwrap().section);
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 0, .. }));
+}
+
+#[test]
+fn test_decode_error_short_section_length() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    // section 3 length
+    bytes[30..33].copy_from_slice(&[0, 0, 3]);
+    let err = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 3, .. }));
+}
+
+#[test]
+fn test_decode_error_position() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let n = bytes.len();
+    bytes[n - 1] = b'8';
+    let err = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap_err();
+    assert_eq!(Some(&Position { message_index: 0, message_offset: 0, section: Some(5), bit_offset: 94 * 8 }),
+               err.position());
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 5, .. }));
+    assert_eq!("message 0 at byte 0, section 5, bit offset 752: section 5: Stop signature expected, found: 7778",
+               err.to_string());
+    assert!(err.source().is_some());
+}
+
+#[test]
+fn test_decode_error_table_not_found() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    // master table version
+    bytes[21] = 99;
+    let err = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap_err();
+    assert_eq!(Some(4), err.position().unwrap().section);
+    assert!(matches!(err.kind(), BufrKitError::TableNotFound { .. }));
+    let io_err = err.source().unwrap().source().unwrap();
+    assert!(io_err.downcast_ref::<std::io::Error>().is_some());
+}
+
+#[test]
+fn test_decode_error_unexpected_eof() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let err = decode_binary(&table_group_manager, &mut &bytes[..50]).unwrap_err();
+    assert_eq!(Some(&Position { message_index: 0, message_offset: 0, section: Some(0), bit_offset: 0 }),
+               err.position());
+    assert!(matches!(err.kind(), BufrKitError::UnexpectedEof));
+}
+
+fn warnings_of(bufr_message: &BufrMessage, kind: WarningKind) -> Vec<&Warning> {
+    bufr_message.warnings().iter().filter(|w| w.kind == kind).collect()
+}
+
+/// A good message, one missing its stop signature, then another good message
+fn corrupt_messages() -> Vec<u8> {
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    let mut bytes = b"junk".to_vec();
+    bytes.extend(&message);
+    bytes.extend(&message[..message.len() - 4]);
+    bytes.extend(&message);
+    bytes
+}
+
+#[test]
+fn test_decode_messages() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    bytes.extend(bytes.clone());
+    let results: Vec<_> = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).collect();
+    assert_eq!(2, results.len());
+    assert!(results.iter().all(|r| r.is_ok()));
+}
+
+#[test]
+fn test_decode_messages_strict() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = corrupt_messages();
+    let results: Vec<_> = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).collect();
+    assert_eq!(2, results.len());
+    assert!(results[0].is_ok());
+    let err = results[1].as_ref().unwrap_err();
+    assert_eq!(1, err.position().unwrap().message_index);
+    assert_eq!(98, err.position().unwrap().message_offset);
+}
+
+#[test]
+fn test_decode_messages_lenient() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = corrupt_messages();
+    let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+    let results: Vec<_> = decode_messages(&table_group_manager, &bytes, &options).collect();
+    assert_eq!(3, results.len());
+    assert!(results[0].is_ok());
+    let err = results[1].as_ref().unwrap_err();
+    assert_eq!(98, err.position().unwrap().message_offset);
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 5, .. }));
+    assert!(results[2].is_ok());
+
+    // Truncated at the end of the input
+    let results: Vec<_> = decode_messages(&table_group_manager, &bytes[..bytes.len() - 10], &options).collect();
+    assert_eq!(3, results.len());
+    assert!(matches!(results[2].as_ref().unwrap_err().kind(), BufrKitError::UnexpectedEof));
+}
+
+#[test]
+fn test_decode_messages_parallel() {
+    let table_group_manager = TableGroupManager::new();
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    let mut many = Vec::new();
+    for i in 0..30 {
+        many.extend(if i == 11 { corrupt_messages() } else { message.clone() });
+    }
+    let summary = |results: Vec<Result<BufrMessage, BufrKitError>>| results.iter()
+        .map(|r| r.as_ref()
+            .map(|m| format!("{:?} {:?} {:?}", m.header(), m.warnings(), subsets_of(m)))
+            .map_err(|e| e.to_string()))
+        .collect::<Vec<_>>();
+    for bytes in [corrupt_messages(), many] {
+        for &lenient in &[false, true] {
+            let options = DecodeOptions { lenient, ..DecodeOptions::default() };
+            let expected = summary(decode_messages(&table_group_manager, &bytes, &options).collect());
+            for &n_threads in &[1, 2, 3, 8] {
+                let results = decode_messages_parallel(&table_group_manager, &bytes, &options, n_threads).collect();
+                assert_eq!(expected, summary(results), "lenient: {}, threads: {}", lenient, n_threads);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decode_stream() {
+    let table_group_manager = TableGroupManager::new();
+    let summary = |results: Vec<Result<BufrMessage, BufrKitError>>| results.iter()
+        .map(|r| r.as_ref()
+            .map(|m| format!("{:?} {:?} {:?}", m.header(), m.warnings(), subsets_of(m)))
+            .map_err(|e| e.to_string()))
+        .collect::<Vec<_>>();
+    let bytes = corrupt_messages();
+    for bytes in [&bytes[..], &bytes[..bytes.len() - 10]] {
+        for &lenient in &[false, true] {
+            let limits = DecodeLimits { max_message_size: 94, ..DecodeLimits::default() };
+            let options = DecodeOptions { lenient, limits, ..DecodeOptions::default() };
+            let expected = summary(decode_messages(&table_group_manager, bytes, &options).collect());
+            // Keeps no more than one message to seek back over
+            let r = Rewind::new(BufReader::with_capacity(16, bytes), options.limits.max_message_size);
+            assert_eq!(expected, summary(decode_stream(&table_group_manager, r, &options).collect()), "lenient: {}", lenient);
+        }
+    }
+}
+
+#[test]
+fn test_scan() {
+    let bytes = corrupt_messages();
+    let summaries: Vec<_> = scan(Cursor::new(&bytes), &DecodeOptions::default()).collect();
+    assert_eq!(2, summaries.len());
+    let summary = summaries[0].as_ref().unwrap();
+    assert_eq!((0, 4, 94), (summary.index, summary.offset, summary.length));
+    assert_eq!((2, false), (summary.n_subsets, summary.is_compressed));
+    assert_eq!(9, summary.unexpanded_descriptors.len());
+    assert_eq!(Fxy::try_from(301001).unwrap(), summary.unexpanded_descriptors[0]);
+    let table_group_manager = TableGroupManager::new();
+    let bufr_message = decode_binary(&table_group_manager, &mut &bytes[..]).unwrap();
+    assert_eq!(bufr_message.header(), &summary.header);
+    let err = summaries[1].as_ref().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 5, .. }));
+    assert_eq!(98, err.position().unwrap().message_offset);
+
+    let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+    let summaries: Vec<_> = scan(BufReader::with_capacity(16, Cursor::new(&bytes)), &options).collect();
+    assert_eq!(3, summaries.len());
+    assert_eq!((2, 188), summaries[2].as_ref().map(|s| (s.index, s.offset)).unwrap());
+}
+
+#[test]
+fn test_scan_without_tables() {
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    // master table version that has no tables
+    bytes[21] = 99;
+    let table_group_manager = TableGroupManager::new();
+    let err = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::TableNotFound { .. }));
+    let summary = scan(Cursor::new(&bytes), &DecodeOptions::default()).next().unwrap().unwrap();
+    assert_eq!(99, summary.header.master_table_version);
+}
+
+#[test]
+fn test_decode_non_zero_reserved_bits() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    // section 3 reserved byte
+    bytes[33] = 1;
+    let err = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 3, .. }));
+
+    let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+    let bufr_message = decode_messages(&table_group_manager, &bytes, &options).next().unwrap().unwrap();
+    let warnings = warnings_of(&bufr_message, WarningKind::ReservedBits);
+    assert_eq!(1, warnings.len());
+    let warning = warnings[0];
+    assert_eq!(WarningKind::ReservedBits, warning.kind);
+    assert_eq!(None, warning.descriptor);
+    assert_eq!(Some(3), warning.position.section);
+    assert_eq!("message 0 at byte 0, section 3, bit offset 272: reserved bits: reserved_bits is 0b1, expected 0",
+               warning.to_string());
+}
+
+#[test]
+fn test_decode_section_length_mismatch() {
+    let table_group_manager = TableGroupManager::new();
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    // An extra byte between section 4 and section 5
+    let n = message.len();
+    let mut bytes = message[..n - 4].to_vec();
+    bytes.push(0);
+    bytes.extend(&message[n - 4..]);
+    bytes[6] += 1;
+    let err = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 4, .. }));
+
+    let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+    let bufr_message = decode_messages(&table_group_manager, &bytes, &options).next().unwrap().unwrap();
+    assert_eq!("1 bytes between the end of section 4 and section 5",
+               warnings_of(&bufr_message, WarningKind::SectionLength)[0].message);
+
+    // section 4 runs into section 5
+    let mut bytes = message;
+    bytes[57] += 1;
+    let err = decode_messages(&table_group_manager, &bytes, &options).next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 4, .. }));
+
+    // section 4 is shorter than its data
+    bytes[57] -= 2;
+    let err = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::InvalidSection { section: 4, .. }));
+}
+
+#[test]
+fn test_decode_section_1_local_bytes() {
+    let table_group_manager = TableGroupManager::new();
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    // Two extra bytes for local use at the end of section 1
+    let mut bytes = message[..30].to_vec();
+    bytes.extend(&[0xff, 0xff]);
+    bytes.extend(&message[30..]);
+    bytes[6] += 2;
+    bytes[10] += 2;
+    let bufr_message = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap();
+    assert!(warnings_of(&bufr_message, WarningKind::SectionLength).is_empty());
+    assert!(warnings_of(&bufr_message, WarningKind::Padding).is_empty());
+    assert_eq!(Some(24), bufr_message.section(1).unwrap().try_length());
+}
+
+#[test]
+fn test_decode_limits() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let decode_with = |limits: DecodeLimits| {
+        let options = DecodeOptions { limits, ..DecodeOptions::default() };
+        decode_messages(&table_group_manager, &bytes, &options).next().unwrap()
+    };
+    assert!(decode_with(DecodeLimits::default()).is_ok());
+
+    let err = decode_with(DecodeLimits { max_message_size: 90, ..DecodeLimits::default() }).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::LimitExceeded { value: 94, max: 90, .. }));
+
+    let err = decode_with(DecodeLimits { max_subsets: 1, ..DecodeLimits::default() }).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::LimitExceeded { value: 2, max: 1, .. }));
+    assert_eq!(Some(3), err.position().unwrap().section);
+
+    let err = decode_with(DecodeLimits { max_expansion_depth: 1, ..DecodeLimits::default() }).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::LimitExceeded { limit: "expansion depth", .. }));
+}
+
+#[test]
+fn test_decode_section_3_padding() {
+    let table_group_manager = TableGroupManager::new();
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    let with_padding = |padding: u8| {
+        let mut bytes = message[..55].to_vec();
+        bytes.push(padding);
+        bytes.extend(&message[55..]);
+        bytes[6] += 1;
+        bytes[32] += 1;
+        bytes
+    };
+
+    let bytes = with_padding(0);
+    let bufr_message = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap();
+    assert!(warnings_of(&bufr_message, WarningKind::Padding).is_empty());
+
+    // Non-zero padding is only a warning, even when strict
+    let bytes = with_padding(0xff);
+    let bufr_message = decode_messages(&table_group_manager, &bytes, &DecodeOptions::default()).next().unwrap().unwrap();
+    assert_eq!(1, warnings_of(&bufr_message, WarningKind::Padding).len());
+}
+
+#[test]
+fn test_decode_payload() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let subsets = bufr_message.section(4).unwrap()
+        .field_by_name("template_data").unwrap()
+        .try_get_subsets().unwrap();
+    assert_eq!(2, subsets.len());
+    let values = |i: usize| subsets[i].iter().map(|v| (u32::from(v.id), v.value.clone())).collect::<Vec<_>>();
+    let first = values(0);
+    assert_eq!(20, first.len());
+    assert_eq!((1001, SimpleData::I64(94)), first[0]);
+    assert_eq!((1002, SimpleData::I64(461)), first[1]);
+    assert_eq!((31001, SimpleData::I64(2)), first[2]);
+    assert_eq!((4001, SimpleData::I64(2016)), first[16]);
+    assert_eq!((20011, SimpleData::U32(1)), first[19]);
+    let second = values(1);
+    assert_eq!((1001, SimpleData::I64(95)), second[0]);
+    assert_eq!((31001, SimpleData::I64(3)), second[2]);
+
+    let warnings = warnings_of(&bufr_message, WarningKind::UnknownCodeValue);
+    assert_eq!(1, warnings.len());
+    assert_eq!(Some(Fxy::try_from(8002).unwrap()), warnings[0].descriptor);
+}
+
+#[test]
+fn test_decode_reuses_templates() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let first = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let second = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let stats = table_group_manager.template_stats();
+    assert_eq!((1, 1, 1), (stats.hits, stats.misses, stats.size));
+    let subsets = |m: &BufrMessage| m.section(4).unwrap()
+        .field_by_name("template_data").unwrap()
+        .try_get_subsets().unwrap().to_vec();
+    assert_eq!(subsets(&first), subsets(&second));
+
+    table_group_manager.clear();
+    assert_eq!(0, table_group_manager.template_stats().size);
+}
+
+#[test]
+fn test_decode_payload_limits() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let decode_with = |limits: DecodeLimits| {
+        let options = DecodeOptions { limits, ..DecodeOptions::default() };
+        decode_messages(&table_group_manager, &bytes, &options).next().unwrap()
+    };
+    let err = decode_with(DecodeLimits { max_replication_count: 2, ..DecodeLimits::default() }).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::LimitExceeded { value: 3, max: 2, .. }));
+    assert_eq!(Some(4), err.position().unwrap().section);
+
+    let err = decode_with(DecodeLimits { max_output_values: 39, ..DecodeLimits::default() }).unwrap_err();
+    assert!(matches!(err.kind(), BufrKitError::LimitExceeded { value: 40, max: 39, .. }));
+    assert!(decode_with(DecodeLimits { max_output_values: 40, ..DecodeLimits::default() }).is_ok());
+}
+
+fn subsets_of(bufr_message: &BufrMessage) -> &[Vec<DataValue>] {
+    bufr_message.section(4).unwrap()
+        .field_by_name("template_data").unwrap()
+        .try_get_subsets().unwrap()
+}
+
+/// The WMO table group of master table version 25
+fn wmo_table_group(table_group_manager: &TableGroupManager) -> Arc<TableGroup> {
+    table_group_manager.get_table_group(&TableGroupId {
+        base_dir: "_definitions/tables".to_owned(),
+        master_table_number: 0,
+        centre_number: 0,
+        sub_centre_number: 0,
+        version_number: 25,
+    }).unwrap()
+}
+
+fn template_of(table_group_manager: &TableGroupManager, bufr_message: &BufrMessage) -> (Arc<TableGroup>, Arc<Template>) {
+    let table_group = table_group_manager.get_table_group(&table_group_id(bufr_message.header())).unwrap();
+    let ids = bufr_message.section(3).unwrap()
+        .field_by_name("unexpanded_descriptors").unwrap()
+        .try_get_unexpanded_descriptors().unwrap();
+    let template = table_group_manager.get_template(&table_group, ids, DEFAULT_MAX_DEPTH).unwrap();
+    (table_group, template)
+}
+
+#[test]
+fn test_program_matches_visitor() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let (table_group, template) = template_of(&table_group_manager, &bufr_message);
+    let program = Program::compile(&template).unwrap();
+
+    let decode = |use_program: bool| {
+        let mut br = BitReader::new(&bytes[59..]);
+        let reader = PayloadReader::new(&table_group, &mut br, 2, false, DecodeLimits::default(), Position::default());
+        if use_program { decode_with_program(&program, reader) } else { decode_with_visitor(&template, reader) }.unwrap()
+    };
+    let (subsets, warnings) = decode(true);
+    assert_eq!(subsets_of(&bufr_message), &subsets[..]);
+    assert_eq!((subsets, warnings), decode(false));
+}
+
+#[test]
+fn test_program_encode() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let bufr_message = decode_binary(&table_group_manager, &mut bytes.as_slice()).unwrap();
+    let (_, template) = template_of(&table_group_manager, &bufr_message);
+    let program = Program::compile(&template).unwrap();
+
+    let encoded = encode_with_program(&program, subsets_of(&bufr_message)).unwrap();
+    assert!(encoded.len() <= 31);
+    assert_eq!(&bytes[59..59 + encoded.len()], &encoded[..]);
+
+    let mut subsets = subsets_of(&bufr_message).to_vec();
+    subsets[0][0].id = Fxy::try_from(1002).unwrap();
+    let err = encode_with_program(&program, &subsets).unwrap_err();
+    assert_eq!("section 4: 001001: subset 0 has a value of 001002 instead", err.to_string());
+    subsets[0][0].id = Fxy::try_from(1001).unwrap();
+    subsets[0].pop();
+    assert!(matches!(encode_with_program(&program, &subsets), Err(BufrKitError::InvalidSection { section: 4, .. })));
+    subsets[0].push(DataValue { id: Fxy::try_from(20011).unwrap(), value: SimpleData::U32(1 << 20) });
+    assert!(encode_with_program(&program, &subsets).is_err());
+}
+
+#[test]
+fn test_program_compile() {
+    let table_group_manager = TableGroupManager::new();
+    let table_group = wmo_table_group(&table_group_manager);
+    let ids: Vec<Fxy> = [201131, 1001, 201000, 103002, 201131, 1001, 201000, 1002].iter()
+        .map(|&id| Fxy::try_from(id).unwrap())
+        .collect();
+    let program = Program::compile(&Template::new(&table_group, &ids).unwrap()).unwrap();
+    let instructions = program.instructions();
+    assert_eq!(9, instructions.len());
+    assert!(matches!(&instructions[0], Instruction::Operator(_)));
+    assert!(matches!(&instructions[1], Instruction::Value(Operand::Resolved(spec)) if spec.nbits == 10));
+    assert!(matches!(&instructions[3], Instruction::BeginReplication { count: 2, end: 7 }));
+    // The operators inside the replication apply differently to each repetition
+    assert!(matches!(&instructions[5], Instruction::Value(Operand::Deferred(_))));
+    assert!(matches!(&instructions[7], Instruction::EndReplication));
+    assert!(matches!(&instructions[8], Instruction::Value(Operand::Deferred(_))));
+}
+
+#[test]
+fn test_decode_subset() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let full = decode_slice(&table_group_manager, &bytes, &DecodeOptions::default()).unwrap();
+    let decode_subset = |index: usize| {
+        let options = DecodeOptions { subset: Some(index), ..DecodeOptions::default() };
+        decode_slice(&table_group_manager, &bytes, &options)
+    };
+    let second = decode_subset(1).unwrap();
+    assert_eq!(full.subset(1), second.subset(1));
+    assert!(second.subset(0).is_none());
+    assert!(second.subset(2).is_none());
+    assert_eq!(1, warnings_of(&second, WarningKind::UnknownCodeValue).len());
+
+    let first = decode_subset(0).unwrap();
+    assert_eq!(full.subset(0), first.subset(0));
+    assert!(first.subset(1).is_none());
+    assert!(first.warnings().is_empty());
+
+    assert!(matches!(decode_subset(2).unwrap_err().kind(), BufrKitError::SubsetOutOfRange { index: 2, n_subsets: 2 }));
+}
+
+#[test]
+fn test_decode_widened_overflow() {
+    let table_group_manager = TableGroupManager::new();
+    let table_group = wmo_table_group(&table_group_manager);
+    // 001001 is 7 bits wide, with a reference value of 0
+    let program_of = |width: u32| {
+        let ids: Vec<Fxy> = [201128 + width - 7, 1001, 201000].iter().map(|&id| Fxy::try_from(id).unwrap()).collect();
+        Program::compile(&Template::new(&table_group, &ids).unwrap()).unwrap()
+    };
+    let decode = |program: &Program, bytes: &[u8], n_subsets: usize, compressed: bool| {
+        let mut br = BitReader::new(bytes);
+        let reader = PayloadReader::new(&table_group, &mut br, n_subsets, compressed, DecodeLimits::default(), Position::default());
+        decode_with_program(program, reader).map(|(subsets, _)| subsets)
+    };
+
+    let bytes = pack_bits(&[((1 << 63) - 2, 63)]);
+    let subsets = decode(&program_of(63), &bytes, 1, false).unwrap();
+    assert_eq!(SimpleData::I64(i64::MAX - 1), subsets[0][0].value);
+
+    let program = program_of(64);
+    let bytes = pack_bits(&[(1 << 63, 64)]);
+    assert!(matches!(decode(&program, &bytes, 1, false), Err(BufrKitError::InvalidSection { section: 4, .. })));
+    // The minimum plus the difference of the first subset overflows
+    let bytes = pack_bits(&[(u64::MAX - 10, 64), (5, 6), (20, 5), (0, 5)]);
+    assert!(matches!(decode(&program, &bytes, 2, true), Err(BufrKitError::InvalidSection { section: 4, .. })));
+}
+
+/// Pack values of the given widths, most significant bit first
+fn pack_bits(values: &[(u64, usize)]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    for &(value, width) in values {
+        writer.write(value, width);
+    }
+    writer.into_bytes()
+}
+
+#[test]
+fn test_decode_compressed_subset() {
+    let table_group_manager = TableGroupManager::new();
+    let table_group = wmo_table_group(&table_group_manager);
+    let ids = [Fxy::try_from(1001).unwrap(), Fxy::try_from(1002).unwrap()];
+    let program = Program::compile(&Template::new(&table_group, &ids).unwrap()).unwrap();
+    // 001001 (7 bits): 10 + [0, 1, 7], with the last missing. 001002 (10 bits): 100 for all.
+    let bytes = pack_bits(&[(10, 7), (3, 6), (0, 3), (1, 3), (7, 3), (100, 10), (0, 6)]);
+
+    let decode = |subset: Option<usize>| {
+        let mut br = BitReader::new(&bytes);
+        let mut reader = PayloadReader::new(&table_group, &mut br, 3, true, DecodeLimits::default(), Position::default());
+        if let Some(index) = subset {
+            reader.select_subset(index).unwrap();
+        }
+        let (subsets, _) = decode_with_program(&program, reader).unwrap();
+        (subsets, br.position())
+    };
+    let (all, n_bits) = decode(None);
+    assert_eq!(3, all.len());
+    assert_eq!(SimpleData::I64(11), all[1][0].value);
+    assert_eq!(SimpleData::MISSING, all[2][0].value);
+    for (i, subset) in all.iter().enumerate() {
+        let (selected, n_selected_bits) = decode(Some(i));
+        assert_eq!(vec![subset.clone()], selected);
+        assert_eq!(n_bits, n_selected_bits);
+    }
+}
+
+#[test]
+fn test_decode_delayed_repetition() {
+    let table_group_manager = TableGroupManager::new();
+    let table_group = wmo_table_group(&table_group_manager);
+    let ids: Vec<Fxy> = [101000, 31011, 1001].iter().map(|&id| Fxy::try_from(id).unwrap()).collect();
+    let template = Template::new(&table_group, &ids).unwrap();
+    let program = Program::compile(&template).unwrap();
+    // 001001 is present once and repeated 3 times in the first subset, and not at all in the second
+    let bytes = pack_bits(&[(3, 8), (94, 7), (0, 8)]);
+
+    let decode = |use_program: bool| {
+        let mut br = BitReader::new(&bytes);
+        let reader = PayloadReader::new(&table_group, &mut br, 2, false, DecodeLimits::default(), Position::default());
+        let (subsets, _) = if use_program {
+            decode_with_program(&program, reader)
+        } else {
+            decode_with_visitor(&template, reader)
+        }.unwrap();
+        assert_eq!(23, br.position());
+        subsets
+    };
+    let subsets = decode(true);
+    let values = |i: usize| subsets[i].iter().map(|v| (u32::from(v.id), v.value.clone())).collect::<Vec<_>>();
+    assert_eq!(vec![(31011, SimpleData::I64(3)), (1001, SimpleData::I64(94)), (1001, SimpleData::I64(94)),
+                    (1001, SimpleData::I64(94))], values(0));
+    assert_eq!(vec![(31011, SimpleData::I64(0))], values(1));
+    assert_eq!(subsets, decode(false));
+
+    assert_eq!(bytes, encode_with_program(&program, &subsets).unwrap());
+    let mut subsets = subsets;
+    subsets[0][3].value = SimpleData::I64(95);
+    assert!(matches!(encode_with_program(&program, &subsets), Err(BufrKitError::InvalidSection { section: 4, .. })));
+}
+
+#[test]
+fn test_decode_unsupported_operator() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    // The last descriptor, 020011, becomes 203000, which changes reference values
+    bytes[53..55].copy_from_slice(&[0x83, 0x00]);
+    for lenient in [false, true] {
+        let options = DecodeOptions { lenient, ..DecodeOptions::default() };
+        let bufr_message = decode_slice(&table_group_manager, &bytes, &options).unwrap();
+        let warnings = warnings_of(&bufr_message, WarningKind::UnsupportedOperator);
+        assert_eq!(1, warnings.len());
+        assert_eq!(Some(Fxy::try_from(203000).unwrap()), warnings[0].descriptor);
+        let field = bufr_message.section(4).unwrap().field_by_name("template_data").unwrap();
+        assert!(field.try_get_subsets().is_none());
+        assert!(matches!(field.try_get_simple_data(), Some(SimpleData::RAW(_, 248))));
+    }
+}
+
+#[test]
+fn test_decode_projection() {
+    let table_group_manager = TableGroupManager::new();
+    let bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    let full = decode_slice(&table_group_manager, &bytes, &DecodeOptions::default()).unwrap();
+    let ids: HashSet<Fxy> = [1001, 1002, 31001].iter().map(|&id| Fxy::try_from(id).unwrap()).collect();
+    let options = DecodeOptions { projection: Some(ids.clone()), ..DecodeOptions::default() };
+    let projected = decode_slice(&table_group_manager, &bytes, &options).unwrap();
+    assert!(projected.warnings().is_empty());
+    for (full_subset, projected_subset) in subsets_of(&full).iter().zip(subsets_of(&projected)) {
+        let expected: Vec<_> = full_subset.iter().filter(|v| ids.contains(&v.id)).cloned().collect();
+        assert!(!expected.is_empty());
+        assert_eq!(&expected, projected_subset);
+    }
+    assert_eq!(SimpleData::I64(95), subsets_of(&projected)[1][0].value);
+
+    let (_, template) = template_of(&table_group_manager, &full);
+    let program = Program::compile(&template).unwrap();
+    let skipped = program.project(&ids, false);
+    assert!(skipped.instructions().len() < program.instructions().len());
+    assert!(skipped.instructions().iter().any(|instruction| matches!(instruction, Instruction::SkipBits(_))));
+    assert!(!program.project(&ids, true).instructions().iter()
+        .any(|instruction| matches!(instruction, Instruction::SkipBits(_))));
+}
+
+#[test]
+fn test_decode_compressed_projection() {
+    let table_group_manager = TableGroupManager::new();
+    let table_group = wmo_table_group(&table_group_manager);
+    let ids = [Fxy::try_from(1001).unwrap(), Fxy::try_from(1002).unwrap()];
+    let program = Program::compile(&Template::new(&table_group, &ids).unwrap()).unwrap();
+    // 001001 (7 bits): 10 + [0, 1, 7]. 001002 (10 bits): 100 + [0, 2, 5].
+    let bytes = pack_bits(&[(10, 7), (3, 6), (0, 3), (1, 3), (7, 3), (100, 10), (3, 6), (0, 3), (2, 3), (5, 3)]);
+
+    let wanted: HashSet<Fxy> = ids[1..].iter().cloned().collect();
+    let mut br = BitReader::new(&bytes);
+    let mut reader = PayloadReader::new(&table_group, &mut br, 3, true, DecodeLimits::default(), Position::default());
+    reader.project(&wanted);
+    let (subsets, _) = decode_with_program(&program.project(&wanted, true), reader).unwrap();
+    let values: Vec<Vec<SimpleData>> = subsets.iter()
+        .map(|subset| subset.iter().map(|v| v.value.clone()).collect())
+        .collect();
+    assert_eq!(vec![vec![SimpleData::I64(100)], vec![SimpleData::I64(102)], vec![SimpleData::I64(105)]], values);
+    assert_eq!(47, br.position());
+}
+
+#[test]
+fn test_index() {
+    let table_group_manager = TableGroupManager::new();
+    let mut bytes = fs::read("tests/data/contrived.bufr").unwrap();
+    bytes.extend_from_within(..);
+    let options = DecodeOptions::default();
+    let mut index = Index::new(SourceInfo { length: bytes.len() as u64, modified: None, compression: None });
+    for summary in scan(Cursor::new(&bytes), &options) {
+        assert!(index.push(summary.unwrap(), &table_group_manager, &options.limits).expansion_error.is_none());
+    }
+    assert_eq!(2, index.entries().len());
+    assert_eq!(94, index.entries()[1].summary.offset);
+    assert_eq!(index.entries()[0].template_hash, index.entries()[1].template_hash);
+    assert_ne!(template_hash(&[Fxy::try_from(1001).unwrap()]), template_hash(&[Fxy::try_from(1002).unwrap()]));
+
+    let mut saved = Vec::new();
+    index.write(&mut saved).unwrap();
+    let index = Index::read(saved.as_slice()).unwrap();
+    assert_eq!(bytes.len() as u64, index.source().length);
+    assert_eq!(index.descriptors_of(&index.entries()[0]), index.descriptors_of(&index.entries()[1]));
+    // Both messages share the one descriptor set saved
+    let saved: serde_json::Value = serde_json::from_slice(&saved).unwrap();
+    assert_eq!(1, saved["descriptors"].as_object().unwrap().len());
+
+    let count = |query: IndexQuery| index.query(&query).count();
+    assert_eq!(2, count(IndexQuery::default()));
+    assert_eq!(2, count(IndexQuery { centre: Some(1), data_category: Some(2), ..IndexQuery::default() }));
+    assert_eq!(0, count(IndexQuery { centre: Some(98), ..IndexQuery::default() }));
+    let day = |s: &str| Some(s.parse::<Timestamp>().unwrap());
+    assert_eq!(2, count(IndexQuery { from: day("2016-02-18"), to: day("2016-02-19"), ..IndexQuery::default() }));
+    assert_eq!(0, count(IndexQuery { from: day("2016-02-18T23:00:01"), ..IndexQuery::default() }));
+    assert_eq!(0, count(IndexQuery { to: day("2016-02-18T23:00"), ..IndexQuery::default() }));
+    // 004001 is only found in the expansion of 301011
+    assert_eq!(2, count(IndexQuery { descriptor: Some(Fxy::try_from(4001).unwrap()), ..IndexQuery::default() }));
+    assert_eq!(0, count(IndexQuery { descriptor: Some(Fxy::try_from(12101).unwrap()), ..IndexQuery::default() }));
+
+    assert!(Index::read(&br#"{"version":0,"source":{"length":0,"modified":null,"compression":null},"descriptors":{},"entries":[]}"#[..]).is_err());
+
+    // A message with a template of its own whose tables are missing is indexed without its descriptors
+    let mut summary = index.entries()[0].summary.clone();
+    summary.header.master_table_version = 99;
+    summary.unexpanded_descriptors.push(Fxy::try_from(1001).unwrap());
+    let mut index = index;
+    let entry = index.push(summary, &table_group_manager, &options.limits).clone();
+    assert!(entry.expansion_error.as_ref().unwrap().contains("table not found"));
+    assert!(index.descriptors_of(&entry).is_none());
+}
+
+#[test]
+fn test_index_source_info() {
+    let path = std::env::temp_dir().join(format!("rustbufrkit-source-{}.bufr", std::process::id()));
+    fs::write(&path, b"BUFR").unwrap();
+    let before = SourceInfo::of(&path).unwrap();
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(before.modified.unwrap() + std::time::Duration::from_secs(10)).unwrap();
+    let after = SourceInfo::of(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    // Same length but modified since
+    assert_eq!(before.length, after.length);
+    assert_ne!(before, after);
+}
+
+#[test]
+fn test_parse_timestamp() {
+    let timestamp = Timestamp { year: 2016, month: 2, day: 18, hour: 23, minute: 0, second: 0 };
+    assert_eq!(timestamp, timestamp.to_string().parse().unwrap());
+    assert_eq!(timestamp, "2016-02-18 23".parse().unwrap());
+    assert_eq!(Timestamp { hour: 0, ..timestamp }, "2016-02-18".parse().unwrap());
+    for s in &["2016-02", "2016-13-01", "2016-02-18T", "2016-02-18T24:00", "2016-02-18T23:00:00:00", "today"] {
+        assert!(s.parse::<Timestamp>().is_err(), "{}", s);
+    }
+}
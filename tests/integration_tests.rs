@@ -1,5 +1,6 @@
 use std::io::BufReader;
-use std::fs::File;
+use std::fs::{self, File};
+use std::process::Command;
 
 use rustbufrkit::decoder::decode_binary;
 use rustbufrkit::table::table::TableGroupManager;
@@ -11,4 +12,43 @@ fn test_decode() {
         File::open("tests/data/contrived.bufr").unwrap());
     let bufr_message = decode_binary(&table_group_manager, &mut r).unwrap();
     println!("{:?}", bufr_message);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_cli_inputs() {
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_rustbufrkit")).args(args).output().unwrap();
+    let output = run(&["ls", "tests/data/contrived.bufr", "tests/data/contrived.bufr.gz"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(2, stdout.lines().count());
+    assert!(stdout.starts_with("tests/data/contrived.bufr\t0\t0\t94\t"));
+
+    let output = run(&["ls", "tests/data/missing.bufr", "tests/data/contrived.bufr"]);
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(1, String::from_utf8(output.stdout).unwrap().lines().count());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("tests/data/missing.bufr: "));
+
+    let output = run(&["index", "build", "-"]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("cannot index stdin"));
+}
+
+#[test]
+fn test_cli_lenient() {
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_rustbufrkit")).args(args).output().unwrap();
+    let path = std::env::temp_dir().join(format!("rustbufrkit-lenient-{}.bufr", std::process::id()));
+    let message = fs::read("tests/data/contrived.bufr").unwrap();
+    // The second message is cut short
+    fs::write(&path, [&message[..], &message[..50]].concat()).unwrap();
+    let path_name = path.to_str().unwrap();
+    let ls = run(&["ls", "--lenient", path_name]);
+    let decode = run(&["decode", "--lenient", path_name]);
+    fs::remove_file(&path).unwrap();
+
+    // The good message is still listed, but the run fails
+    assert_eq!(Some(1), ls.status.code());
+    assert_eq!(1, String::from_utf8(ls.stdout).unwrap().lines().count());
+    assert!(String::from_utf8(ls.stderr).unwrap().ends_with("1 message(s) failed to scan\n"));
+    assert_eq!(Some(1), decode.status.code());
+    assert!(String::from_utf8(decode.stderr).unwrap().ends_with("1 message(s) failed to decode\n"));
+}